@@ -0,0 +1,152 @@
+"use strict";
+
+const MAX_EVENTS = 50;
+
+let source = null;
+let lastSequence = null;
+let guardDeadline = null;
+
+function setting(name) {
+  return localStorage.getItem(name) || "";
+}
+
+function show(id, text, state) {
+  const element = document.getElementById(id);
+  element.textContent = text;
+  element.className = state;
+}
+
+function showBool(id, value, onText, offText) {
+  if (value === null || value === undefined) {
+    show(id, "?", "unknown");
+  } else {
+    show(id, value ? onText : offText, value ? "on" : "off");
+  }
+}
+
+function remaining(ms) {
+  return ms === null ? "" : ` (${Math.ceil(ms / 1000)}s left)`;
+}
+
+function addEvent(text, timestamp) {
+  const item = document.createElement("li");
+  const time = document.createElement("time");
+  time.textContent = new Date(timestamp).toLocaleTimeString();
+  item.append(time, text);
+
+  const events = document.getElementById("events");
+  events.prepend(item);
+  while (events.children.length > MAX_EVENTS) {
+    events.lastChild.remove();
+  }
+}
+
+function update(response) {
+  const status = response.status;
+
+  showBool("tx_power_enabled", status.tx_power_enabled, "Enabled", "Disabled");
+  document.getElementById("tx_power_enabled").textContent += remaining(status.tx_power_enable_remaining_ms);
+  showBool("tx_power_active", status.tx_power_active, "On", "Off");
+  showBool("ptt_enabled", status.ptt_enabled, "Enabled", "Disabled");
+  document.getElementById("ptt_enabled").textContent += remaining(status.ptt_enable_remaining_ms);
+  showBool("ptt_active", status.ptt_active, "Transmitting", "Idle");
+
+  show("lockout", status.lockout || "None", status.lockout ? "on" : "off");
+  show(
+    "interlocks",
+    status.interlocks_tripped.join(", ") || "None",
+    status.interlocks_tripped.length ? "on" : "off",
+  );
+  show("mqtt_broker", status.mqtt_broker || "Disconnected", status.mqtt_broker ? "off" : "on");
+
+  const inputs = document.getElementById("inputs");
+  inputs.replaceChildren();
+  for (const [name, value] of Object.entries(status.inputs)) {
+    const state = document.createElement("div");
+    state.className = "state";
+    const label = document.createElement("span");
+    label.textContent = name;
+    const b = document.createElement("b");
+    b.id = `input_${name}`;
+    state.append(label, b);
+    inputs.append(state);
+    showBool(b.id, value, "Active", "Inactive");
+  }
+
+  guardDeadline =
+    status.tx_guard_remaining_ms === null ? null : Date.now() + status.tx_guard_remaining_ms;
+
+  // Statuses may be sent again when reconnecting
+  if (response.message && response.sequence !== lastSequence) {
+    addEvent(response.message, response.timestamp);
+  }
+  lastSequence = response.sequence;
+}
+
+function showGuard() {
+  if (guardDeadline === null) {
+    show("tx_guard", "-", "unknown");
+  } else {
+    const seconds = Math.max(0, (guardDeadline - Date.now()) / 1000);
+    show("tx_guard", `${seconds.toFixed(1)}s`, "on");
+  }
+}
+
+function connect() {
+  if (source) {
+    source.close();
+  }
+
+  const token = setting("token");
+  source = new EventSource(token ? `events?token=${encodeURIComponent(token)}` : "events");
+  source.addEventListener("status", (e) => update(JSON.parse(e.data)));
+  source.onopen = () => show("connection", "Connected", "off");
+  source.onerror = () => show("connection", "Disconnected", "on");
+}
+
+async function send(path, body, description) {
+  if (!confirm(`${description}?`)) {
+    return;
+  }
+
+  const headers = { "Content-Type": "application/json" };
+  if (setting("token")) {
+    headers.Authorization = `Bearer ${setting("token")}`;
+  }
+
+  try {
+    const response = await fetch(path, { method: "POST", headers, body });
+    if (response.status === 401) {
+      addEvent(`${description}: not authorised, check the API token`, Date.now());
+      return;
+    }
+    if (!response.ok && response.status !== 400) {
+      addEvent(`${description}: ${await response.text()}`, Date.now());
+      return;
+    }
+
+    const reply = await response.json();
+    const details = reply.error
+      ? [reply.error]
+      : reply.results.map((r) => `${r.action} ${r.outcome}${r.reason ? ` (${r.reason})` : ""}`);
+    addEvent(`${description}: ${reply.outcome}, ${details.join(", ")}`, reply.timestamp);
+  } catch (e) {
+    addEvent(`${description}: ${e}`, Date.now());
+  }
+}
+
+document.getElementById("closedown").onclick = () => send("closedown", null, "Close down");
+document.getElementById("enable").onclick = () =>
+  send("command", JSON.stringify({ enable_tx_power: true, enable_ptt: true }), "Enable TX power and PTT");
+document.getElementById("reset_lockout").onclick = () =>
+  send("command", JSON.stringify({ reset_lockout: true }), "Reset lockout");
+
+document.getElementById("token").value = setting("token");
+document.getElementById("settings").onsubmit = (e) => {
+  e.preventDefault();
+  localStorage.setItem("token", document.getElementById("token").value);
+  connect();
+};
+
+setInterval(showGuard, 100);
+connect();
@@ -0,0 +1,240 @@
+use crate::{
+    event::{Event, MqttMessageEvent},
+    metrics::METRICS,
+    schema::{ActionOutcome, ActionResult, CommandResponse, Output},
+};
+use std::collections::HashMap;
+use tokio::{
+    sync::broadcast::Sender,
+    task::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// How long to wait for outputs to confirm they have been set before acknowledging a command anyway.
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where to send the reply to a command.
+pub(crate) enum Reply {
+    /// Publish to an MQTT response topic
+    Mqtt {
+        topic: String,
+        correlation_data: Option<Vec<u8>>,
+    },
+    /// Return to the local API that sent the command, by request ID
+    Local(u64),
+}
+
+impl Reply {
+    pub(crate) fn mqtt(topic: &str, request: &MqttMessageEvent) -> Self {
+        Self::Mqtt {
+            topic: topic.to_string(),
+            correlation_data: request.correlation_data.clone(),
+        }
+    }
+}
+
+struct PendingAck {
+    id: u64,
+    reply: Reply,
+    request_id: Option<String>,
+    results: Vec<ActionResult>,
+    timeout_task: JoinHandle<()>,
+}
+
+/// Tracks commands that are waiting on their actions to complete before being acknowledged.
+#[derive(Default)]
+pub(crate) struct CommandAcks {
+    next_id: u64,
+    pending: Vec<PendingAck>,
+    /// When each output was first asked to change by a command that it hasn't yet confirmed, for
+    /// command latency (whether or not the command is replied to)
+    awaiting_outputs: HashMap<Output, Instant>,
+}
+
+fn send_response(tx: &Sender<Event>, reply: Reply, response: CommandResponse) {
+    match reply {
+        Reply::Mqtt {
+            topic,
+            correlation_data,
+        } => match serde_json::to_string(&response) {
+            Ok(payload) => crate::send_event!(
+                tx,
+                Event::MqttMessageSend(
+                    MqttMessageEvent::new(&topic, &payload).with_correlation_data(correlation_data)
+                )
+            ),
+            Err(e) => log::error!("Failed building command response message: {}", e),
+        },
+        Reply::Local(id) => crate::send_event!(tx, Event::LocalCommandResponse(id, response)),
+    }
+}
+
+impl CommandAcks {
+    /// Acknowledges a command once all of its actions have completed.
+    pub(crate) fn add(
+        &mut self,
+        tx: &Sender<Event>,
+        reply: Option<Reply>,
+        request_id: Option<String>,
+        results: Vec<ActionResult>,
+    ) {
+        for (output, _) in results.iter().filter_map(|r| r.awaiting) {
+            self.awaiting_outputs
+                .entry(output)
+                .or_insert_with(Instant::now);
+        }
+
+        let Some(reply) = reply else {
+            return;
+        };
+
+        if !results.iter().any(|r| r.outcome == ActionOutcome::Pending) {
+            send_response(tx, reply, CommandResponse::new(request_id, results));
+            return;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let timeout_task = {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(CONFIRMATION_TIMEOUT).await;
+                crate::send_event!(tx, Event::CommandAckTimeout(id));
+            })
+        };
+
+        self.pending.push(PendingAck {
+            id,
+            reply,
+            request_id,
+            results,
+            timeout_task,
+        });
+    }
+
+    /// Replies to a command that could not be understood.
+    pub(crate) fn reject(
+        &self,
+        tx: &Sender<Event>,
+        reply: Option<Reply>,
+        request_id: Option<String>,
+        error: String,
+    ) {
+        if let Some(reply) = reply {
+            send_response(tx, reply, CommandResponse::rejected(request_id, error));
+        }
+    }
+
+    /// Resolves the oldest pending action that is waiting on an output, with the state the output
+    /// has been set to or the reason it could not be set.
+    pub(crate) fn confirm(
+        &mut self,
+        tx: &Sender<Event>,
+        output: Output,
+        result: Result<bool, String>,
+    ) {
+        if let Some(dispatched) = self.awaiting_outputs.remove(&output) {
+            if result.is_ok() {
+                METRICS
+                    .command_latency
+                    .observe(dispatched.elapsed().as_secs_f64());
+            }
+        }
+
+        let waiting = |awaiting: &(Output, bool)| {
+            awaiting.0 == output && result.as_ref().map_or(true, |state| *state == awaiting.1)
+        };
+
+        let Some(idx) = self.pending.iter().position(|ack| {
+            ack.results
+                .iter()
+                .any(|r| r.awaiting.as_ref().is_some_and(waiting))
+        }) else {
+            return;
+        };
+
+        let ack = &mut self.pending[idx];
+        if let Some(action) = ack
+            .results
+            .iter_mut()
+            .find(|r| r.awaiting.as_ref().is_some_and(waiting))
+        {
+            action.awaiting = None;
+            match result {
+                Err(reason) => {
+                    action.outcome = ActionOutcome::Failed;
+                    action.reason = Some(reason);
+                }
+                Ok(_) => action.outcome = ActionOutcome::Applied,
+            }
+        }
+
+        if !ack
+            .results
+            .iter()
+            .any(|r| r.outcome == ActionOutcome::Pending)
+        {
+            self.complete(tx, idx);
+        }
+    }
+
+    /// Acknowledges a command whose outputs did not confirm in time.
+    pub(crate) fn timeout(&mut self, tx: &Sender<Event>, id: u64) {
+        if let Some(idx) = self.pending.iter().position(|ack| ack.id == id) {
+            for result in self.pending[idx]
+                .results
+                .iter_mut()
+                .filter(|r| r.outcome == ActionOutcome::Pending)
+            {
+                result.awaiting = None;
+                result.outcome = ActionOutcome::Unconfirmed;
+                result.reason = Some("No confirmation from output".to_string());
+            }
+            self.complete(tx, idx);
+        }
+    }
+
+    fn complete(&mut self, tx: &Sender<Event>, idx: usize) {
+        let ack = self.pending.remove(idx);
+        ack.timeout_task.abort();
+        send_response(
+            tx,
+            ack.reply,
+            CommandResponse::new(ack.request_id, ack.results),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    #[tokio::test]
+    async fn command_latency_observed() {
+        let (tx, _rx) = broadcast::channel(16);
+        let mut acks = CommandAcks::default();
+        let samples = || METRICS.command_latency.get_sample_count();
+
+        // Not replied to
+        let before = samples();
+        let results = vec![ActionResult::dispatched(&Event::SetPttEnable(true))];
+        acks.add(&tx, None, None, results);
+        acks.confirm(&tx, Output::PttEnable, Ok(true));
+        assert!(samples() > before);
+
+        // Replied to
+        let before = samples();
+        let results = vec![ActionResult::dispatched(&Event::SetTxPowerEnable(false))];
+        acks.add(&tx, Some(Reply::Local(1)), None, results);
+        acks.confirm(&tx, Output::TxPowerEnable, Ok(false));
+        assert!(samples() > before);
+
+        // Not set, so there is no latency to observe
+        let results = vec![ActionResult::dispatched(&Event::SetPttEnable(true))];
+        acks.add(&tx, None, None, results);
+        acks.confirm(&tx, Output::PttEnable, Err("failed".to_string()));
+        assert!(acks.awaiting_outputs.is_empty());
+    }
+}
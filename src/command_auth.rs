@@ -0,0 +1,469 @@
+use crate::{
+    config::{default_replay_window, CommandSigning, Config, Permission},
+    event::{Event, MqttMessageEvent},
+    schema::{Command, SignedCommand},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+enum Key {
+    Hmac(Vec<u8>),
+    Ed25519(VerifyingKey),
+}
+
+impl Key {
+    fn verify(&self, payload: &[u8], signature: &[u8]) -> Result<()> {
+        match self {
+            Key::Hmac(key) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+                mac.update(payload);
+                mac.verify_slice(signature)?;
+            }
+            Key::Ed25519(key) => {
+                key.verify(payload, &Signature::from_slice(signature)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Permission needed to carry out the action of an event.
+fn required_permission(event: &Event) -> Option<Permission> {
+    match event {
+        Event::SetTxPowerEnable(false)
+        | Event::SetPttEnable(false)
+        | Event::SetTxPowerEnableTimeout(None)
+        | Event::SetPttEnableTimeout(None) => Some(Permission::Closedown),
+        Event::SetTxPowerEnable(true)
+        | Event::SetPttEnable(true)
+        | Event::SetTxPowerEnableTimeout(Some(_))
+        | Event::SetPttEnableTimeout(Some(_))
+        | Event::ExtendEnableTimeouts(_) => Some(Permission::Enable),
+        Event::ResetLockout => Some(Permission::ResetLockout),
+        _ => None,
+    }
+}
+
+/// A command that has passed signature and replay checks (if required).
+#[derive(Debug)]
+pub(crate) struct AuthenticatedCommand {
+    /// Name of the operator that sent the command, `None` if it could not be identified
+    pub operator: Option<String>,
+    pub command: Command,
+}
+
+/// Checks signatures on command messages, rejects replayed commands and checks operator
+/// permissions.
+pub(crate) struct CommandVerifier {
+    signing: Option<CommandSigning>,
+    keys: HashMap<String, Vec<Key>>,
+    permissions: HashMap<String, Vec<Permission>>,
+    anonymous_permissions: Vec<Permission>,
+    operator_subtopics: bool,
+    trust_operator_claims: bool,
+    /// Nonces of recently accepted commands (by operator) and the timestamps of those commands
+    seen_nonces: HashMap<(String, String), i64>,
+}
+
+impl CommandVerifier {
+    pub(crate) fn new(config: &Config) -> Result<Self> {
+        let mut keys = HashMap::new();
+        let mut permissions = HashMap::new();
+
+        for operator in &config.operators {
+            let mut operator_keys = Vec::new();
+
+            if let Some(ref key) = operator.hmac_key {
+                operator_keys.push(Key::Hmac(key.expose().as_bytes().to_vec()));
+            }
+
+            if let Some(ref key) = operator.ed25519_public_key {
+                let invalid = || {
+                    format!(
+                        "Invalid Ed25519 public key for operator \"{}\"",
+                        operator.name
+                    )
+                };
+                let key: [u8; 32] = hex::decode(key)
+                    .ok()
+                    .and_then(|k| k.try_into().ok())
+                    .ok_or_else(|| anyhow!(invalid()))?;
+                operator_keys.push(Key::Ed25519(
+                    VerifyingKey::from_bytes(&key).with_context(invalid)?,
+                ));
+            }
+
+            if keys.insert(operator.name.clone(), operator_keys).is_some() {
+                bail!("Duplicate operator \"{}\"", operator.name);
+            }
+
+            permissions.insert(
+                operator.name.clone(),
+                operator
+                    .permissions
+                    .clone()
+                    .unwrap_or_else(|| Permission::ALL.to_vec()),
+            );
+        }
+
+        Ok(Self {
+            signing: config.command_signing.clone(),
+            keys,
+            permissions,
+            anonymous_permissions: config
+                .anonymous_permissions
+                .clone()
+                .unwrap_or_else(|| Permission::ALL.to_vec()),
+            operator_subtopics: config.mqtt.operator_subtopics,
+            trust_operator_claims: config.mqtt.trust_operator_claims,
+            seen_nonces: HashMap::new(),
+        })
+    }
+
+    /// Parses a command message, verifying its signature if it is signed.
+    pub(crate) fn verify(&mut self, message: &str) -> Result<AuthenticatedCommand> {
+        let required = self.signing.as_ref().is_some_and(|s| s.required);
+        self.verify_message(message, required)
+    }
+
+    /// Parses a command message from a sender that has already been authenticated as an operator
+    /// (e.g. by a local API), so need not be signed even if signing is required.
+    pub(crate) fn verify_from_operator(&mut self, message: &str) -> Result<AuthenticatedCommand> {
+        self.verify_message(message, false)
+    }
+
+    fn verify_message(&mut self, message: &str, required: bool) -> Result<AuthenticatedCommand> {
+        let signed = serde_json::from_str::<serde_json::Value>(message)
+            .is_ok_and(|v| v.get("signature").is_some());
+
+        if !signed {
+            if required {
+                bail!("command is not signed");
+            }
+            return Ok(AuthenticatedCommand {
+                operator: None,
+                command: serde_json::from_str(message)?,
+            });
+        }
+
+        let signed: SignedCommand = serde_json::from_str(message)?;
+
+        let keys = self
+            .keys
+            .get(&signed.operator)
+            .ok_or_else(|| anyhow!("unknown operator \"{}\"", signed.operator))?;
+
+        let signature =
+            hex::decode(&signed.signature).map_err(|_| anyhow!("signature is not hex encoded"))?;
+
+        if !keys
+            .iter()
+            .any(|key| key.verify(signed.payload.as_bytes(), &signature).is_ok())
+        {
+            bail!("invalid signature from operator \"{}\"", signed.operator);
+        }
+
+        let command: Command = serde_json::from_str(&signed.payload)?;
+        self.check_replay(&signed.operator, &command)?;
+
+        Ok(AuthenticatedCommand {
+            operator: Some(signed.operator),
+            command,
+        })
+    }
+
+    /// Identifies the operator of a command from its signature, the MQTT v5 `operator` user
+    /// property or the operator subtopic of the command topic it was received on (if enabled).
+    ///
+    /// Anyone can claim to be an operator, so unsigned commands may only do so if claims are
+    /// trusted.
+    pub(crate) fn identify(
+        &self,
+        command: AuthenticatedCommand,
+        request: &MqttMessageEvent,
+        command_topic: &str,
+    ) -> Result<AuthenticatedCommand> {
+        let subtopic = request
+            .topic()
+            .strip_prefix(command_topic)
+            .and_then(|t| t.strip_prefix('/'))
+            .filter(|_| self.operator_subtopics);
+        let claims = [request.operator.as_deref(), subtopic];
+
+        if command.operator.is_none() && !self.trust_operator_claims {
+            if let Some(claimed) = claims.iter().flatten().next() {
+                bail!(
+                    "unsigned command claims to be from operator \"{}\"",
+                    claimed
+                );
+            }
+        }
+
+        Ok(AuthenticatedCommand {
+            operator: self.resolve_operator(command.operator.clone(), &claims)?,
+            ..command
+        })
+    }
+
+    /// Combines the operator that signed a request (if any) with the operators it claims to be from,
+    /// which must all be known and agree with each other.
+    pub(crate) fn resolve_operator(
+        &self,
+        signed: Option<String>,
+        claims: &[Option<&str>],
+    ) -> Result<Option<String>> {
+        let mut operator = signed;
+        for claimed in claims.iter().flatten() {
+            if !self.permissions.contains_key(*claimed) {
+                bail!("unknown operator \"{}\"", claimed);
+            }
+            match operator {
+                Some(ref operator) if operator != claimed => {
+                    bail!("conflicting operators \"{}\" and \"{}\"", operator, claimed);
+                }
+                _ => operator = Some(claimed.to_string()),
+            }
+        }
+        Ok(operator)
+    }
+
+    /// Checks if an operator (or an unidentified sender if `None`) may carry out the action of an
+    /// event.
+    pub(crate) fn permitted(&self, operator: Option<&str>, event: &Event) -> bool {
+        let Some(required) = required_permission(event) else {
+            return true;
+        };
+
+        operator
+            .and_then(|o| self.permissions.get(o))
+            .unwrap_or(&self.anonymous_permissions)
+            .contains(&required)
+    }
+
+    fn check_replay(&mut self, operator: &str, command: &Command) -> Result<()> {
+        let (Some(timestamp), Some(nonce)) = (command.timestamp, command.nonce.as_ref()) else {
+            bail!("signed command must have a timestamp and nonce");
+        };
+
+        // Signed commands are accepted without a `[command_signing]` section, with the default window
+        let window = match self.signing {
+            Some(ref signing) => signing.replay_window,
+            None => default_replay_window(),
+        }
+        .unwrap_or_default()
+        .as_millis() as i64;
+        let now = chrono::Utc::now().timestamp_millis();
+
+        if (now - timestamp).abs() > window {
+            bail!("command timestamp is outside of the replay window");
+        }
+
+        self.seen_nonces.retain(|_, t| now - *t <= window);
+
+        if self
+            .seen_nonces
+            .insert((operator.to_string(), nonce.clone()), timestamp)
+            .is_some()
+        {
+            bail!("command has already been received (replayed nonce)");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Operator;
+    use ed25519_dalek::{Signer, SigningKey};
+    use tokio::time::Duration;
+
+    fn config() -> Config {
+        Config {
+            operators: vec![
+                Operator {
+                    name: "alice".to_string(),
+                    hmac_key: Some("secret".into()),
+                    ..Default::default()
+                },
+                Operator {
+                    name: "bob".to_string(),
+                    ed25519_public_key: Some(hex::encode(
+                        SigningKey::from_bytes(&[7; 32]).verifying_key().as_bytes(),
+                    )),
+                    ..Default::default()
+                },
+            ],
+            command_signing: Some(CommandSigning {
+                required: true,
+                replay_window: Some(Duration::from_secs(30)),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn payload(nonce: &str, timestamp: i64) -> String {
+        format!(
+            "{{\"enable_ptt\":false,\"nonce\":\"{}\",\"timestamp\":{}}}",
+            nonce, timestamp
+        )
+    }
+
+    fn hmac_signed(key: &str, payload: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        serde_json::json!({
+            "operator": "alice",
+            "signature": hex::encode(mac.finalize().into_bytes()),
+            "payload": payload,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn hmac_signed_commands() {
+        let mut verifier = CommandVerifier::new(&config()).unwrap();
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let msg = hmac_signed("secret", &payload("1", now));
+        let cmd = verifier.verify(&msg).unwrap();
+        assert_eq!(Some("alice".to_string()), cmd.operator);
+
+        // Replayed
+        assert!(verifier.verify(&msg).is_err());
+
+        // Wrong key
+        assert!(verifier
+            .verify(&hmac_signed("wrong", &payload("2", now)))
+            .is_err());
+
+        // Too old
+        assert!(verifier
+            .verify(&hmac_signed("secret", &payload("3", now - 60000)))
+            .is_err());
+
+        // Unsigned
+        assert!(verifier.verify("{\"enable_ptt\":false}").is_err());
+    }
+
+    #[test]
+    fn signed_without_signing_section() {
+        let mut verifier = CommandVerifier::new(&Config {
+            command_signing: None,
+            ..config()
+        })
+        .unwrap();
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let cmd = verifier
+            .verify(&hmac_signed("secret", &payload("1", now - 1000)))
+            .unwrap();
+        assert_eq!(Some("alice".to_string()), cmd.operator);
+
+        // Still subject to the default replay window
+        assert!(verifier
+            .verify(&hmac_signed("secret", &payload("2", now - 60000)))
+            .is_err());
+
+        // Signing isn't required
+        assert!(verifier.verify("{\"enable_ptt\":false}").is_ok());
+    }
+
+    #[test]
+    fn operator_permissions() {
+        let mut config = Config {
+            operators: vec![
+                Operator {
+                    name: "alice".to_string(),
+                    permissions: Some(vec![Permission::Closedown]),
+                    ..Default::default()
+                },
+                Operator {
+                    name: "bob".to_string(),
+                    ..Default::default()
+                },
+            ],
+            anonymous_permissions: Some(Vec::new()),
+            ..Default::default()
+        };
+        config.mqtt.operator_subtopics = true;
+        config.mqtt.trust_operator_claims = true;
+
+        let identify = |config: &Config, topic: &str, operator: Option<&str>| {
+            let mut request = MqttMessageEvent::new(topic, "{}");
+            request.operator = operator.map(|o| o.to_string());
+            CommandVerifier::new(config)
+                .unwrap()
+                .identify(verifier_command(), &request, "station/command")
+                .map(|c| c.operator)
+        };
+
+        assert_eq!(None, identify(&config, "station/command", None).unwrap());
+        assert_eq!(
+            Some("alice".to_string()),
+            identify(&config, "station/command/alice", None).unwrap()
+        );
+        assert_eq!(
+            Some("bob".to_string()),
+            identify(&config, "station/command", Some("bob")).unwrap()
+        );
+        assert!(identify(&config, "station/command/alice", Some("bob")).is_err());
+        assert!(identify(&config, "station/command/eve", None).is_err());
+
+        // Unsigned claims are refused unless trusted
+        config.mqtt.trust_operator_claims = false;
+        assert_eq!(None, identify(&config, "station/command", None).unwrap());
+        assert!(identify(&config, "station/command/alice", None).is_err());
+        assert!(identify(&config, "station/command", Some("bob")).is_err());
+
+        // The subtopic is only an operator if operator subtopics are enabled
+        config.mqtt.trust_operator_claims = true;
+        config.mqtt.operator_subtopics = false;
+        assert_eq!(
+            None,
+            identify(&config, "station/command/alice", None).unwrap()
+        );
+
+        let verifier = CommandVerifier::new(&config).unwrap();
+
+        assert!(verifier.permitted(Some("alice"), &Event::SetPttEnable(false)));
+        assert!(!verifier.permitted(Some("alice"), &Event::SetPttEnable(true)));
+        assert!(!verifier.permitted(Some("alice"), &Event::ResetLockout));
+        assert!(verifier.permitted(Some("bob"), &Event::ResetLockout));
+        assert!(!verifier.permitted(None, &Event::SetPttEnable(false)));
+        assert!(verifier.permitted(None, &Event::SendStats));
+    }
+
+    fn verifier_command() -> AuthenticatedCommand {
+        AuthenticatedCommand {
+            operator: None,
+            command: serde_json::from_str("{}").unwrap(),
+        }
+    }
+
+    #[test]
+    fn ed25519_signed_commands() {
+        let mut verifier = CommandVerifier::new(&config()).unwrap();
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let payload = payload("1", chrono::Utc::now().timestamp_millis());
+
+        let signed = |operator: &str| {
+            serde_json::json!({
+                "operator": operator,
+                "signature": hex::encode(key.sign(payload.as_bytes()).to_bytes()),
+                "payload": payload,
+            })
+            .to_string()
+        };
+
+        // Signed by bob's key but claiming to be alice
+        assert!(verifier.verify(&signed("alice")).is_err());
+
+        let cmd = verifier.verify(&signed("bob")).unwrap();
+        assert_eq!(Some("bob".to_string()), cmd.operator);
+    }
+}
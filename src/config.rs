@@ -1,4 +1,5 @@
-use anyhow::Result;
+use crate::rules::{self, Expression};
+use anyhow::{bail, Result};
 use serde::Deserialize;
 use std::fs;
 use tokio::time::Duration;
@@ -15,18 +16,355 @@ mod duration_format {
     }
 }
 
+mod duration_list_format {
+    use serde::{self, Deserialize, Deserializer};
+    use tokio::time::Duration;
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<u64>::deserialize(deserializer)?
+            .into_iter()
+            .map(Duration::from_millis)
+            .collect())
+    }
+}
+
+/// A secret config value, given either directly or as a reference to a file or environment variable
+/// (e.g. `{ file = "/run/credentials/password" }` or `{ env = "MQTT_PASSWORD" }`) which is resolved
+/// when the config is loaded.
+#[derive(Clone, Default, PartialEq)]
+pub(crate) struct Secret(String);
+
+impl Secret {
+    pub(crate) fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(s: &str) -> Self {
+        Self(s.to_string())
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Source {
+            Value(String),
+            File { file: String },
+            Env { env: String },
+        }
+
+        Ok(Self(match Source::deserialize(deserializer)? {
+            Source::Value(value) => value,
+            Source::File { file } => fs::read_to_string(&file)
+                .map_err(|e| {
+                    D::Error::custom(format!("failed to read secret \"{}\": {}", file, e))
+                })?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+            Source::Env { env } => std::env::var(&env).map_err(|e| {
+                D::Error::custom(format!("failed to read secret from \"{}\": {}", env, e))
+            })?,
+        }))
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Default, Debug, Deserialize)]
+pub(crate) struct Tls {
+    pub ca_file: Option<String>,
+    pub ca_path: Option<String>,
+
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub client_key_password: Option<Secret>,
+
+    #[serde(default)]
+    pub alpn: Vec<String>,
+
+    #[serde(default = "default_true")]
+    pub verify_server_cert: bool,
+    #[serde(default = "default_true")]
+    pub verify_hostname: bool,
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StatusFormat {
+    /// Status as a single JSON document on the status topic
+    #[default]
+    Json,
+    /// Each status field on its own retained subtopic of the status topic
+    Fields,
+    Both,
+}
+
+impl StatusFormat {
+    pub(crate) fn json(&self) -> bool {
+        matches!(self, Self::Json | Self::Both)
+    }
+
+    pub(crate) fn fields(&self) -> bool {
+        matches!(self, Self::Fields | Self::Both)
+    }
+}
+
+/// Substitutes the `{callsign}`, `{station}` and `{hostname}` placeholders in a topic.
+fn expand_topic(topic: &str, callsign: Option<&str>, station: Option<&str>) -> Result<String> {
+    let mut expanded = topic.to_string();
+
+    for (placeholder, value) in [("{callsign}", callsign), ("{station}", station)] {
+        if expanded.contains(placeholder) {
+            match value {
+                Some(value) => expanded = expanded.replace(placeholder, value),
+                None => bail!("Topic \"{}\" uses {} but it is not set", topic, placeholder),
+            }
+        }
+    }
+
+    if expanded.contains("{hostname}") {
+        let hostname = hostname::get()?;
+        expanded = expanded.replace("{hostname}", &hostname.to_string_lossy());
+    }
+
+    Ok(expanded)
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+#[derive(Clone, Default, Debug, Deserialize)]
+pub(crate) struct HomeAssistant {
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+
+    /// Unique ID of the station, used for the discovery topics and entity IDs
+    pub node_id: String,
+
+    pub device_name: String,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+}
+
+fn default_homie_base_topic() -> String {
+    "homie".to_string()
+}
+
+#[derive(Clone, Default, Debug, Deserialize)]
+pub(crate) struct Homie {
+    #[serde(default = "default_homie_base_topic")]
+    pub base_topic: String,
+
+    /// Homie device ID, lowercase letters, digits and hyphens only
+    pub device_id: String,
+    pub name: String,
+}
+
+impl Homie {
+    pub(crate) fn device_topic(&self) -> String {
+        format!("{}/{}", self.base_topic, self.device_id)
+    }
+}
+
+#[derive(Clone, Default, Debug, Deserialize)]
+pub(crate) struct Broker {
+    pub uri: String,
+
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: Secret,
+
+    pub tls: Option<Tls>,
+}
+
 #[derive(Clone, Default, Debug, Deserialize)]
 pub(crate) struct Mqtt {
+    #[serde(default)]
     pub broker: String,
     pub client_id: String,
 
     #[serde(default)]
     pub username: String,
     #[serde(default)]
-    pub password: String,
+    pub password: Secret,
+
+    pub tls: Option<Tls>,
+
+    /// Brokers in order of preference, in place of `broker`, `username`, `password` and `tls`
+    #[serde(default)]
+    pub brokers: Vec<Broker>,
 
+    pub callsign: Option<String>,
+    pub station: Option<String>,
+    /// Topic that the other topics are derived from if they are not given
+    pub base_topic: Option<String>,
+
+    #[serde(default)]
     pub status_topic: String,
+    #[serde(default)]
+    pub status_format: StatusFormat,
+    #[serde(default)]
     pub command_topic: String,
+    /// Additional topics (or topic filters) to accept commands on
+    #[serde(default)]
+    pub extra_command_topics: Vec<String>,
+    /// Also accept commands on `<command_topic>/<operator>`, identifying the operator
+    #[serde(default)]
+    pub operator_subtopics: bool,
+    /// Accept operators claimed by unsigned commands (by user property or operator subtopic)
+    #[serde(default)]
+    pub trust_operator_claims: bool,
+
+    pub alarm_topic: Option<String>,
+    #[serde(default)]
+    pub alarm_retain: bool,
+
+    pub stats_topic: Option<String>,
+    pub log_topic: Option<String>,
+    pub response_topic: Option<String>,
+
+    pub home_assistant: Option<HomeAssistant>,
+    pub homie: Option<Homie>,
+}
+
+impl Mqtt {
+    /// Expands placeholders in all topics and derives any that are not given from the base topic.
+    fn resolve_topics(&mut self) -> Result<()> {
+        let callsign = self.callsign.clone();
+        let station = self.station.clone();
+        let expand = |topic: &str| expand_topic(topic, callsign.as_deref(), station.as_deref());
+
+        let base_topic = self.base_topic.as_deref().map(expand).transpose()?;
+        let derived = |name: &str| base_topic.as_ref().map(|b| format!("{}/{}", b, name));
+
+        for (topic, name) in [
+            (&mut self.status_topic, "status"),
+            (&mut self.command_topic, "command"),
+        ] {
+            *topic = match (topic.is_empty(), derived(name)) {
+                (false, _) => expand(topic)?,
+                (true, Some(derived)) => derived,
+                (true, None) => bail!("mqtt.{}_topic or mqtt.base_topic must be given", name),
+            };
+        }
+
+        for (topic, name) in [
+            (&mut self.alarm_topic, "alarm"),
+            (&mut self.stats_topic, "stats"),
+            (&mut self.log_topic, "log"),
+        ] {
+            *topic = match topic {
+                Some(topic) => Some(expand(topic)?),
+                None => derived(name),
+            };
+        }
+
+        if let Some(ref mut topic) = self.response_topic {
+            *topic = expand(topic)?;
+        }
+
+        for topic in &mut self.extra_command_topics {
+            *topic = expand(topic)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn brokers(&self) -> Vec<Broker> {
+        match self.brokers.is_empty() {
+            true => vec![Broker {
+                uri: self.broker.clone(),
+                username: self.username.clone(),
+                password: self.password.clone(),
+                tls: self.tls.clone(),
+            }],
+            false => self.brokers.clone(),
+        }
+    }
+
+    pub(crate) fn status_field_topic(&self, field: &str) -> String {
+        format!("{}/{}", self.status_topic, field)
+    }
+
+    /// Whether the controller publishes to a topic, so that a message received on it (through a broad
+    /// extra command topic filter) is its own and not a command.
+    pub(crate) fn is_own_topic(&self, topic: &str) -> bool {
+        let under = |base: &str| {
+            topic
+                .strip_prefix(base)
+                .is_some_and(|t| t.is_empty() || t.starts_with('/'))
+        };
+
+        if under(&self.command_topic) {
+            return false;
+        }
+
+        [
+            Some(&self.status_topic),
+            self.alarm_topic.as_ref(),
+            self.stats_topic.as_ref(),
+            self.log_topic.as_ref(),
+            self.response_topic.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .any(|t| under(t))
+            || self
+                .homie
+                .as_ref()
+                .is_some_and(|h| under(&h.device_topic()) && !topic.ends_with("/set"))
+            || self.home_assistant.as_ref().is_some_and(|ha| {
+                let mut levels = topic.split('/');
+                levels.next() == Some(&ha.discovery_prefix)
+                    && levels.nth(1) == Some(&ha.node_id)
+                    && topic.ends_with("/config")
+            })
+    }
+
+    /// Topic that availability is published to, the Homie `$state` attribute if Homie is enabled.
+    pub(crate) fn availability_topic(&self) -> String {
+        match self.homie {
+            Some(ref homie) => format!("{}/$state", homie.device_topic()),
+            None => self.status_field_topic("availability"),
+        }
+    }
+
+    /// Availability payloads for (online, offline).
+    pub(crate) fn availability_payloads(&self) -> (&'static str, &'static str) {
+        match self.homie {
+            Some(_) => ("ready", "lost"),
+            None => ("online", "offline"),
+        }
+    }
+
+    /// Whether availability is published on its own topic (and used as the last will).
+    ///
+    /// JSON status keeps its offline status as the last will unless Home Assistant or Homie need
+    /// availability.
+    pub(crate) fn availability_enabled(&self) -> bool {
+        !self.status_format.json() || self.home_assistant.is_some() || self.homie.is_some()
+    }
 }
 
 #[derive(Clone, Default, Debug, Deserialize)]
@@ -37,6 +375,153 @@ pub(crate) struct IoPin {
     pub inverted: bool,
 }
 
+#[derive(Clone, Default, Debug, Deserialize)]
+pub(crate) struct NamedIoPin {
+    pub name: String,
+
+    #[serde(flatten)]
+    pub pin: IoPin,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RuleAction {
+    SetTxPowerEnable(bool),
+    SetPttEnable(bool),
+    SendMessage(String),
+    Lockout(String),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Rule {
+    pub name: String,
+    pub condition: Expression,
+
+    #[serde(default, with = "duration_format")]
+    pub hold_time: Option<Duration>,
+
+    pub actions: Vec<RuleAction>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Permission {
+    /// Disable TX power or PTT, cancel timed enables
+    Closedown,
+    /// Enable TX power or PTT, set or extend timed enables
+    Enable,
+    ResetLockout,
+}
+
+impl Permission {
+    pub(crate) const ALL: &'static [Permission] = &[
+        Permission::Closedown,
+        Permission::Enable,
+        Permission::ResetLockout,
+    ];
+}
+
+#[derive(Clone, Default, Debug, Deserialize)]
+pub(crate) struct Operator {
+    pub name: String,
+
+    /// Permissions of the operator, all permissions if not set
+    pub permissions: Option<Vec<Permission>>,
+
+    /// Shared secret for HMAC-SHA256 signed commands
+    pub hmac_key: Option<Secret>,
+    /// Hex encoded public key for Ed25519 signed commands
+    pub ed25519_public_key: Option<String>,
+}
+
+pub(crate) fn default_replay_window() -> Option<Duration> {
+    Some(Duration::from_secs(30))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct CommandSigning {
+    /// Refuse commands that are not signed
+    #[serde(default = "default_true")]
+    pub required: bool,
+
+    /// How far a signed command's timestamp may be from the current time
+    #[serde(default = "default_replay_window", with = "duration_format")]
+    pub replay_window: Option<Duration>,
+}
+
+fn default_http_bind() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_http_port() -> u16 {
+    8080
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Http {
+    #[serde(default = "default_http_bind")]
+    pub bind: String,
+    #[serde(default = "default_http_port")]
+    pub port: u16,
+
+    /// Bearer token accepted for anonymous requests
+    pub token: Option<Secret>,
+    /// Tokens accepted for requests from operators
+    #[serde(default)]
+    pub users: Vec<HttpUser>,
+
+    /// Serve the web dashboard
+    #[serde(default = "default_true")]
+    pub dashboard: bool,
+}
+
+impl Http {
+    /// Whether requests must give a token, they are not authenticated if no tokens are set.
+    pub(crate) fn authenticated(&self) -> bool {
+        self.token.is_some() || !self.users.is_empty()
+    }
+
+    /// Whether the API is only reachable from this machine.
+    fn loopback(&self) -> bool {
+        self.bind == "localhost"
+            || self
+                .bind
+                .parse::<std::net::IpAddr>()
+                .is_ok_and(|a| a.is_loopback())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct HttpUser {
+    pub token: Secret,
+    /// Operator that requests with the token act as, anonymous if not set
+    pub operator: Option<String>,
+}
+
+/// Path of the control socket if not configured, also used by the command line client.
+pub(crate) const DEFAULT_CONTROL_SOCKET: &str = "/run/remote-closedown.sock";
+
+fn default_control_socket_path() -> String {
+    DEFAULT_CONTROL_SOCKET.to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct SocketUser {
+    pub uid: u32,
+    /// Operator that the user acts as, anonymous if not set
+    pub operator: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ControlSocket {
+    #[serde(default = "default_control_socket_path")]
+    pub path: String,
+
+    /// Users allowed to use the socket, in addition to the user the controller runs as
+    #[serde(default)]
+    pub users: Vec<SocketUser>,
+}
+
 #[derive(Clone, Default, Debug, Deserialize)]
 pub(crate) struct Config {
     pub mqtt: Mqtt,
@@ -47,12 +532,210 @@ pub(crate) struct Config {
     pub ptt_enable: Option<IoPin>,
     pub ptt_status: Option<IoPin>,
 
+    #[serde(default)]
+    pub interlocks: Vec<NamedIoPin>,
+
+    #[serde(default)]
+    pub inputs: Vec<NamedIoPin>,
+
     #[serde(default, with = "duration_format")]
     pub tx_guard_time: Option<Duration>,
+
+    #[serde(default, with = "duration_list_format")]
+    pub tx_guard_warnings: Vec<Duration>,
+    pub tx_guard_warning_output: Option<IoPin>,
+    #[serde(default, with = "duration_format")]
+    pub tx_guard_warning_pulse_time: Option<Duration>,
+
+    #[serde(default, with = "duration_format")]
+    pub closedown_failure_time: Option<Duration>,
+
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+
+    #[serde(default)]
+    pub operators: Vec<Operator>,
+    /// Permissions of commands that cannot be attributed to an operator, all permissions if not set
+    pub anonymous_permissions: Option<Vec<Permission>>,
+    pub command_signing: Option<CommandSigning>,
+
+    #[serde(default, with = "duration_format")]
+    pub stats_interval: Option<Duration>,
+
+    /// Interval at which status is published even if nothing has changed
+    #[serde(default, with = "duration_format")]
+    pub status_interval: Option<Duration>,
+
+    pub http: Option<Http>,
+    pub control_socket: Option<ControlSocket>,
 }
 
 impl Config {
     pub fn from_file(filename: &str) -> Result<Self> {
-        Ok(toml::from_str(&fs::read_to_string(filename)?)?)
+        let mut config: Self = toml::from_str(&fs::read_to_string(filename)?)?;
+        config.mqtt.resolve_topics()?;
+
+        match (
+            config.mqtt.broker.is_empty(),
+            config.mqtt.brokers.is_empty(),
+        ) {
+            (true, true) => bail!("No MQTT broker configured"),
+            (false, false) => bail!("Only one of mqtt.broker and mqtt.brokers may be given"),
+            _ => {}
+        }
+
+        if let Some(ref homie) = config.mqtt.homie {
+            if homie.device_id.is_empty()
+                || !homie
+                    .device_id
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            {
+                bail!("Invalid Homie device ID \"{}\"", homie.device_id);
+            }
+        }
+
+        for rule in &config.rules {
+            rules::validate(rule, &config)?;
+        }
+
+        if let Some(ref http) = config.http {
+            if !http.authenticated() && !http.loopback() {
+                bail!(
+                    "http.token must be set to bind the HTTP API to \"{}\"",
+                    http.bind
+                );
+            }
+            for operator in http.users.iter().flat_map(|u| &u.operator) {
+                if !config.operators.iter().any(|o| &o.name == operator) {
+                    bail!("HTTP user has unknown operator \"{}\"", operator);
+                }
+            }
+        }
+
+        if let Some(ref control_socket) = config.control_socket {
+            for operator in control_socket.users.iter().flat_map(|u| &u.operator) {
+                if !config.operators.iter().any(|o| &o.name == operator) {
+                    bail!("Control socket user has unknown operator \"{}\"", operator);
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Whether any API other than MQTT is enabled, which needs to be kept informed of status.
+    pub(crate) fn local_api_enabled(&self) -> bool {
+        self.http.is_some() || self.control_socket.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_references() {
+        // Unique to this test (and process), as tests run in parallel
+        let file = std::env::temp_dir().join(format!(
+            "remote-closedown-secret-references-{}",
+            std::process::id()
+        ));
+        fs::write(&file, "from file\n").unwrap();
+        let var = format!("REMOTE_CLOSEDOWN_SECRET_REFERENCES_{}", std::process::id());
+        std::env::set_var(&var, "from env");
+
+        let broker: Broker = toml::from_str(&format!(
+            "uri = \"tcp://localhost\"\npassword = {{ file = \"{}\" }}",
+            file.display()
+        ))
+        .unwrap();
+        assert_eq!("from file", broker.password.expose());
+        assert!(!format!("{:?}", broker).contains("from file"));
+
+        let broker: Broker = toml::from_str(&format!(
+            "uri = \"tcp://localhost\"\npassword = {{ env = \"{}\" }}",
+            var
+        ))
+        .unwrap();
+        assert_eq!("from env", broker.password.expose());
+
+        let broker: Broker =
+            toml::from_str("uri = \"tcp://localhost\"\npassword = \"inline\"").unwrap();
+        assert_eq!("inline", broker.password.expose());
+
+        assert!(toml::from_str::<Broker>(
+            "uri = \"tcp://localhost\"\npassword = { env = \"REMOTE_CLOSEDOWN_SECRET_REFERENCES_MISSING\" }"
+        )
+        .is_err());
+
+        fs::remove_file(file).unwrap();
+        std::env::remove_var(&var);
+    }
+
+    #[test]
+    fn topic_templates() {
+        let mut mqtt = Mqtt {
+            callsign: Some("gb3xx".to_string()),
+            base_topic: Some("repeaters/{callsign}".to_string()),
+            command_topic: "repeaters/{callsign}/control".to_string(),
+            extra_command_topics: vec!["repeaters/all/command".to_string()],
+            ..Default::default()
+        };
+        mqtt.resolve_topics().unwrap();
+
+        assert_eq!("repeaters/gb3xx/status", mqtt.status_topic);
+        assert_eq!("repeaters/gb3xx/control", mqtt.command_topic);
+        assert_eq!(Some("repeaters/gb3xx/alarm".to_string()), mqtt.alarm_topic);
+        assert_eq!(Some("repeaters/gb3xx/stats".to_string()), mqtt.stats_topic);
+        assert_eq!(Some("repeaters/gb3xx/log".to_string()), mqtt.log_topic);
+        assert_eq!(None, mqtt.response_topic);
+        assert_eq!(vec!["repeaters/all/command"], mqtt.extra_command_topics);
+
+        let mut mqtt = Mqtt {
+            base_topic: Some("repeaters/{station}".to_string()),
+            ..Default::default()
+        };
+        assert!(mqtt.resolve_topics().is_err());
+
+        let mut mqtt = Mqtt {
+            status_topic: "status".to_string(),
+            ..Default::default()
+        };
+        assert!(mqtt.resolve_topics().is_err());
+    }
+
+    #[test]
+    fn own_topics() {
+        let mut mqtt = Mqtt {
+            base_topic: Some("repeaters/gb3xx".to_string()),
+            extra_command_topics: vec!["repeaters/#".to_string()],
+            home_assistant: Some(HomeAssistant {
+                discovery_prefix: "homeassistant".to_string(),
+                node_id: "gb3xx".to_string(),
+                ..Default::default()
+            }),
+            homie: Some(Homie {
+                base_topic: "homie".to_string(),
+                device_id: "gb3xx".to_string(),
+                name: "GB3XX".to_string(),
+            }),
+            ..Default::default()
+        };
+        mqtt.resolve_topics().unwrap();
+
+        assert!(mqtt.is_own_topic("repeaters/gb3xx/status"));
+        assert!(mqtt.is_own_topic("repeaters/gb3xx/status/ptt_active"));
+        assert!(mqtt.is_own_topic("repeaters/gb3xx/alarm"));
+        assert!(mqtt.is_own_topic("repeaters/gb3xx/log"));
+        assert!(mqtt.is_own_topic("homie/gb3xx/ptt/enable"));
+        assert!(mqtt.is_own_topic("homeassistant/switch/gb3xx/ptt_enabled/config"));
+
+        assert!(!mqtt.is_own_topic("repeaters/gb3xx/command"));
+        assert!(!mqtt.is_own_topic("repeaters/gb3xx/command/alice"));
+        assert!(!mqtt.is_own_topic("repeaters/gb3xx/statuses"));
+        assert!(!mqtt.is_own_topic("repeaters/all/command"));
+        assert!(!mqtt.is_own_topic("homie/gb3xx/ptt/enable/set"));
+        assert!(!mqtt.is_own_topic("homeassistant/switch/gb3yy/ptt_enabled/config"));
     }
 }
@@ -0,0 +1,320 @@
+use crate::{
+    config::ControlSocket,
+    event::{Event, LocalRequest},
+    local_api,
+    schema::{ControlRequest, Response},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use nix::unistd::geteuid;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::ErrorKind,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::broadcast::{error::RecvError, Sender},
+    task::JoinHandle,
+};
+
+const SOURCE: &str = "control socket";
+
+/// Operator that a peer acts as, or an error if the peer may not use the socket.
+fn peer_operator(config: &ControlSocket, uid: u32) -> Result<Option<String>> {
+    match config.users.iter().find(|u| u.uid == uid) {
+        Some(user) => Ok(user.operator.clone()),
+        None if uid == geteuid().as_raw() => Ok(None),
+        None => bail!("User {} may not use the control socket", uid),
+    }
+}
+
+async fn handle_request(
+    tx: &Sender<Event>,
+    status: &Mutex<Option<Response>>,
+    operator: Option<String>,
+    line: &str,
+) -> Result<Value> {
+    let request = match serde_json::from_str(line)? {
+        ControlRequest::Status => {
+            let status = status.lock().unwrap().clone();
+            return Ok(serde_json::to_value(
+                status.ok_or_else(|| anyhow!("No status published yet"))?,
+            )?);
+        }
+        ControlRequest::Command { command } => LocalRequest::Command(command.to_string()),
+        ControlRequest::Closedown => LocalRequest::Closedown,
+    };
+
+    let response = local_api::request(tx, SOURCE, operator, request)
+        .await
+        .ok_or_else(|| anyhow!("No reply to command"))?;
+    Ok(serde_json::to_value(response)?)
+}
+
+/// Replies to each request line on a connection with a line of JSON.
+async fn serve(
+    tx: Sender<Event>,
+    config: Arc<ControlSocket>,
+    status: Arc<Mutex<Option<Response>>>,
+    stream: UnixStream,
+) -> Result<()> {
+    let uid = stream.peer_cred()?.uid();
+    let (read, mut write) = stream.into_split();
+
+    let permitted = peer_operator(&config, uid);
+    if let Err(ref e) = permitted {
+        log::warn!(target: "audit", "Refused control socket connection: {}", e);
+    }
+
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match permitted {
+            Ok(ref operator) => handle_request(&tx, &status, operator.clone(), &line)
+                .await
+                .unwrap_or_else(|e| json!({ "error": e.to_string() })),
+            Err(ref e) => json!({ "error": e.to_string() }),
+        };
+        write.write_all(format!("{}\n", reply).as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn run(tx: Sender<Event>, config: &ControlSocket) -> Result<JoinHandle<()>> {
+    // A socket left behind by a previous run would prevent binding, but one that is still accepting
+    // connections belongs to a controller that is running
+    if fs::symlink_metadata(&config.path).is_ok_and(|m| m.file_type().is_socket()) {
+        match std::os::unix::net::UnixStream::connect(&config.path) {
+            Ok(_) => bail!(
+                "Control socket \"{}\" is in use by another controller",
+                config.path
+            ),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => fs::remove_file(&config.path)?,
+            // Binding gives a clearer error
+            Err(_) => {}
+        }
+    }
+
+    let listener = UnixListener::bind(&config.path)
+        .with_context(|| format!("Failed to bind control socket \"{}\"", config.path))?;
+    // Anyone may connect, access is checked against the user of each peer
+    fs::set_permissions(&config.path, fs::Permissions::from_mode(0o666))?;
+    log::info!("Control socket listening on \"{}\"", config.path);
+
+    let mut rx = tx.subscribe();
+    let config = Arc::new(config.clone());
+    let status = Arc::new(Mutex::new(None));
+
+    Ok(tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Ok(Event::StatusPublished(response)) => {
+                        *status.lock().unwrap() = Some(response);
+                    }
+                    Ok(Event::Exit) | Err(RecvError::Closed) => {
+                        if let Err(e) = fs::remove_file(&config.path) {
+                            log::warn!("Failed to remove control socket: {}", e);
+                        }
+                        log::debug!("Task exit");
+                        return;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => {
+                        // Statuses may have been missed, but each one is complete
+                        rx = rx.resubscribe();
+                    }
+                },
+                connection = listener.accept() => match connection {
+                    Ok((stream, _)) => {
+                        let (tx, config, status) = (tx.clone(), config.clone(), status.clone());
+                        tokio::spawn(async move {
+                            if let Err(e) = serve(tx, config, status, stream).await {
+                                log::warn!("Control socket connection failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => log::error!("Failed to accept control socket connection: {}", e),
+                },
+            }
+        }
+    }))
+}
+
+/// Sends a request to the control socket of a running controller and gives its reply.
+pub(crate) async fn request(path: &str, request: &ControlRequest) -> Result<Value> {
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("Failed to connect to control socket \"{}\"", path))?;
+    let (read, mut write) = stream.into_split();
+    write
+        .write_all(format!("{}\n", serde_json::to_string(request)?).as_bytes())
+        .await?;
+
+    let line = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("No reply from controller"))?;
+    let reply: Value = serde_json::from_str(&line)?;
+
+    match reply["error"].as_str() {
+        Some(error) => bail!("{}", error),
+        None => Ok(reply),
+    }
+}
+
+/// Text of a status value, for showing on the command line.
+fn display(value: &Value) -> String {
+    match value {
+        Value::Null => "unknown".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Array(a) if a.is_empty() => "none".to_string(),
+        Value::Array(a) => a.iter().map(display).collect::<Vec<_>>().join(", "),
+        v => v.to_string(),
+    }
+}
+
+/// Prints a status reply as one line per field.
+pub(crate) fn print_status(reply: &Value) {
+    if let Some(message) = reply["message"].as_str() {
+        println!("{}", message);
+    }
+    for (field, value) in reply["status"].as_object().into_iter().flatten() {
+        match value {
+            Value::Object(map) => {
+                for (name, value) in map {
+                    println!("{} {}: {}", field, name, display(value));
+                }
+            }
+            value => println!("{}: {}", field, display(value)),
+        }
+    }
+}
+
+/// Prints a command reply, failing unless every action was applied.
+pub(crate) fn print_command_response(reply: &Value) -> Result<()> {
+    for result in reply["results"].as_array().into_iter().flatten() {
+        match result["reason"].as_str() {
+            Some(reason) => println!(
+                "{}: {} ({})",
+                display(&result["action"]),
+                display(&result["outcome"]),
+                reason
+            ),
+            None => println!(
+                "{}: {}",
+                display(&result["action"]),
+                display(&result["outcome"])
+            ),
+        }
+    }
+
+    match reply["outcome"].as_str() {
+        Some("accepted") => Ok(()),
+        _ => bail!("Command {}", display(&reply["outcome"]).replace('_', " ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::SocketUser,
+        schema::{ActionResult, CommandResponse, Status},
+    };
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn peer_users() {
+        let own = geteuid().as_raw();
+        let mut config = ControlSocket {
+            path: String::new(),
+            users: vec![SocketUser {
+                uid: own + 1,
+                operator: Some("alice".to_string()),
+            }],
+        };
+
+        assert_eq!(None, peer_operator(&config, own).unwrap());
+        assert_eq!(
+            Some("alice".to_string()),
+            peer_operator(&config, own + 1).unwrap()
+        );
+        assert!(peer_operator(&config, own + 2).is_err());
+
+        config.users[0].uid = own;
+        assert_eq!(
+            Some("alice".to_string()),
+            peer_operator(&config, own).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn status_and_closedown() {
+        let path = std::env::temp_dir()
+            .join(format!("remote-closedown-{}.sock", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let config = ControlSocket {
+            path: path.clone(),
+            users: Vec::new(),
+        };
+
+        let (tx, _) = broadcast::channel(16);
+        let mut rx = tx.subscribe();
+        let task = run(tx.clone(), &config).unwrap();
+
+        // Already in use
+        assert!(run(tx.clone(), &config).is_err());
+
+        assert!(request(&path, &ControlRequest::Status).await.is_err());
+
+        let status = Response::new(
+            Status {
+                ptt_enabled: Some(true),
+                ..Default::default()
+            },
+            None,
+        );
+        tx.send(Event::StatusPublished(status)).unwrap();
+        // The task may not have seen the status yet
+        let mut reply = request(&path, &ControlRequest::Status).await;
+        for _ in 0..100 {
+            if reply.is_ok() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            reply = request(&path, &ControlRequest::Status).await;
+        }
+        let reply = reply.unwrap();
+        assert_eq!(json!(true), reply["status"]["ptt_enabled"]);
+
+        // Act as processing, replying to the request
+        let processing = tokio::spawn(async move {
+            loop {
+                if let Ok(Event::LocalCommand(request)) = rx.recv().await {
+                    assert_eq!(SOURCE, request.source);
+                    assert_eq!(None, request.operator);
+                    assert!(matches!(request.request, LocalRequest::Closedown));
+                    let results = vec![ActionResult::dispatched(&Event::SendStats)];
+                    let response = CommandResponse::new(None, results);
+                    tx.send(Event::LocalCommandResponse(request.id, response))
+                        .unwrap();
+                    tx.send(Event::Exit).unwrap();
+                    return;
+                }
+            }
+        });
+
+        let reply = request(&path, &ControlRequest::Closedown).await.unwrap();
+        assert_eq!(json!("accepted"), reply["outcome"]);
+
+        processing.await.unwrap();
+        task.await.unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
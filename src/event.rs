@@ -1,9 +1,20 @@
-use paho_mqtt::Message;
+use crate::schema::{CommandResponse, Output, Response};
+use paho_mqtt::{Message, MessageBuilder, Properties, PropertyCode};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::Duration;
 
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) struct MqttMessageEvent {
     topic: String,
     pub message: String,
+    retain: bool,
+
+    /// MQTT v5 response topic
+    pub response_topic: Option<String>,
+    /// MQTT v5 correlation data
+    pub correlation_data: Option<Vec<u8>>,
+    /// MQTT v5 `operator` user property
+    pub operator: Option<String>,
 }
 
 impl MqttMessageEvent {
@@ -11,6 +22,28 @@ impl MqttMessageEvent {
         Self {
             topic: topic.to_string(),
             message: message.to_string(),
+            retain: false,
+            response_topic: None,
+            correlation_data: None,
+            operator: None,
+        }
+    }
+
+    pub(crate) fn new_retained(topic: &str, message: &str) -> Self {
+        Self {
+            retain: true,
+            ..Self::new(topic, message)
+        }
+    }
+
+    pub(crate) fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub(crate) fn with_correlation_data(self, correlation_data: Option<Vec<u8>>) -> Self {
+        Self {
+            correlation_data,
+            ..self
         }
     }
 }
@@ -20,26 +53,109 @@ impl From<Message> for MqttMessageEvent {
         Self {
             topic: msg.topic().to_string(),
             message: msg.payload_str().to_string(),
+            retain: msg.retained(),
+            response_topic: msg.properties().get_string(PropertyCode::ResponseTopic),
+            correlation_data: msg.properties().get_binary(PropertyCode::CorrelationData),
+            operator: msg.properties().find_user_property("operator"),
         }
     }
 }
 
 impl From<MqttMessageEvent> for Message {
     fn from(msg: MqttMessageEvent) -> Self {
-        Self::new(msg.topic, msg.message, 2)
+        let mut properties = Properties::new();
+        if let Some(correlation_data) = msg.correlation_data {
+            if let Err(e) = properties.push_binary(PropertyCode::CorrelationData, correlation_data)
+            {
+                log::error!("Failed to set correlation data: {}", e);
+            }
+        }
+
+        MessageBuilder::new()
+            .topic(msg.topic)
+            .payload(msg.message)
+            .qos(2)
+            .retained(msg.retain)
+            .properties(properties)
+            .finalize()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum LocalRequest {
+    /// A command message, as would be received on the command topic
+    Command(String),
+    /// Disable TX power and PTT
+    Closedown,
+}
+
+/// A request from a local API (i.e. not via MQTT), replied to with `Event::LocalCommandResponse`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LocalCommandEvent {
+    pub id: u64,
+    /// Name of the API the request was received on, for logging
+    pub source: &'static str,
+    /// Operator the request claims to be from
+    pub operator: Option<String>,
+    pub request: LocalRequest,
+}
+
+impl LocalCommandEvent {
+    pub(crate) fn new(
+        source: &'static str,
+        operator: Option<String>,
+        request: LocalRequest,
+    ) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            source,
+            operator,
+            request,
+        }
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) enum Event {
+    /// Messages are now being sent via the given broker
+    MqttConnected(String),
+    MqttDisconnected,
     MqttMessageReceive(MqttMessageEvent),
     MqttMessageSend(MqttMessageEvent),
+    LocalCommand(LocalCommandEvent),
+    LocalCommandResponse(u64, CommandResponse),
+    /// Status that has just been published, sent only when a local API is enabled
+    StatusPublished(Response),
     SetTxPowerEnable(bool),
     TxPowerEnableStateChanged(bool),
+    TxPowerEnableFailed(String),
     TxPowerStateChanged(bool),
     SetPttEnable(bool),
+    SetTxPowerEnableTimeout(Option<Duration>),
+    SetPttEnableTimeout(Option<Duration>),
+    ExtendEnableTimeouts(Duration),
     PttEnableStateChanged(bool),
+    PttEnableFailed(String),
     PttStateChanged(bool),
+    TxGuardWarning(Duration),
+    TxGuardTripped,
+    InterlockStateChanged(String, bool),
+    InputStateChanged(String, bool),
+    /// A rule (by name) asking for an output to be enabled, which is refused in the same way as
+    /// an enable command
+    RuleEnable(String, Output),
+    Lockout(String),
+    ResetLockout,
+    CommandAckTimeout(u64),
     SendStatus(Option<String>),
+    SendAlarm(String),
+    /// An alarm condition has cleared, removing any retained alarm
+    ClearAlarm(String),
+    SendStats,
+    /// Requests that inputs and outputs report their current state again, after events may have
+    /// been missed
+    Resync,
     Exit,
 }
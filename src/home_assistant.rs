@@ -0,0 +1,179 @@
+use crate::config::{HomeAssistant, Mqtt};
+use anyhow::Result;
+use paho_mqtt::Message;
+use serde_json::json;
+
+enum Component {
+    Switch { command: &'static str },
+    BinarySensor { device_class: &'static str },
+}
+
+struct Entity {
+    field: &'static str,
+    name: &'static str,
+    component: Component,
+}
+
+const ENTITIES: &[Entity] = &[
+    Entity {
+        field: "tx_power_enabled",
+        name: "TX power enable",
+        component: Component::Switch {
+            command: "enable_tx_power",
+        },
+    },
+    Entity {
+        field: "ptt_enabled",
+        name: "PTT enable",
+        component: Component::Switch {
+            command: "enable_ptt",
+        },
+    },
+    Entity {
+        field: "tx_power_active",
+        name: "TX power",
+        component: Component::BinarySensor {
+            device_class: "power",
+        },
+    },
+    Entity {
+        field: "ptt_active",
+        name: "PTT",
+        component: Component::BinarySensor {
+            device_class: "running",
+        },
+    },
+];
+
+/// Builds the retained Home Assistant discovery config messages for the station's entities.
+///
+/// Entity state is taken from the per-field status topics when they are published, otherwise from
+/// the JSON status.
+pub(crate) fn discovery_messages(config: &Mqtt, ha: &HomeAssistant) -> Result<Vec<Message>> {
+    let mut device = json!({
+        "identifiers": [ha.node_id],
+        "name": ha.device_name,
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    // Home Assistant rejects null device fields, so optional ones are left out when unset
+    for (field, value) in [("manufacturer", &ha.manufacturer), ("model", &ha.model)] {
+        if let Some(value) = value {
+            device[field] = json!(value);
+        }
+    }
+
+    let (online, offline) = config.availability_payloads();
+
+    ENTITIES
+        .iter()
+        .map(|entity| {
+            let mut payload = json!({
+                "name": entity.name,
+                "unique_id": format!("{}_{}", ha.node_id, entity.field),
+                "object_id": format!("{}_{}", ha.node_id, entity.field),
+                "device": device,
+                "availability_topic": config.availability_topic(),
+                "payload_available": online,
+                "payload_not_available": offline,
+            });
+            let fields = payload.as_object_mut().unwrap();
+
+            match config.status_format.fields() {
+                true => {
+                    fields.insert(
+                        "state_topic".into(),
+                        config.status_field_topic(entity.field).into(),
+                    );
+                }
+                false => {
+                    fields.insert("state_topic".into(), config.status_topic.clone().into());
+                    fields.insert(
+                        "value_template".into(),
+                        format!(
+                            "{{% if value_json.status.{0} is none %}}None\
+                             {{% elif value_json.status.{0} %}}true\
+                             {{% else %}}false{{% endif %}}",
+                            entity.field
+                        )
+                        .into(),
+                    );
+                }
+            }
+
+            let component = match entity.component {
+                Component::Switch { command } => {
+                    fields.insert("command_topic".into(), config.command_topic.clone().into());
+                    fields.insert(
+                        "payload_on".into(),
+                        json!({ command: true }).to_string().into(),
+                    );
+                    fields.insert(
+                        "payload_off".into(),
+                        json!({ command: false }).to_string().into(),
+                    );
+                    fields.insert("state_on".into(), "true".into());
+                    fields.insert("state_off".into(), "false".into());
+                    "switch"
+                }
+                Component::BinarySensor { device_class } => {
+                    fields.insert("device_class".into(), device_class.into());
+                    fields.insert("payload_on".into(), "true".into());
+                    fields.insert("payload_off".into(), "false".into());
+                    "binary_sensor"
+                }
+            };
+
+            Ok(Message::new_retained(
+                format!(
+                    "{}/{}/{}/{}/config",
+                    ha.discovery_prefix, component, ha.node_id, entity.field
+                ),
+                serde_json::to_string(&payload)?,
+                1,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StatusFormat;
+    use serde_json::Value;
+
+    #[test]
+    fn discovery_config() {
+        let ha = HomeAssistant {
+            discovery_prefix: "homeassistant".into(),
+            node_id: "gb3xx".into(),
+            device_name: "GB3XX".into(),
+            ..Default::default()
+        };
+        let config = Mqtt {
+            status_topic: "station/status".into(),
+            status_format: StatusFormat::Fields,
+            command_topic: "station/command".into(),
+            home_assistant: Some(ha.clone()),
+            ..Default::default()
+        };
+
+        let messages = discovery_messages(&config, &ha).unwrap();
+        assert_eq!(messages.len(), 4);
+
+        let ptt_enable = &messages[1];
+        assert_eq!(
+            ptt_enable.topic(),
+            "homeassistant/switch/gb3xx/ptt_enabled/config"
+        );
+        assert!(ptt_enable.retained());
+
+        let payload: Value = serde_json::from_str(&ptt_enable.payload_str()).unwrap();
+        assert_eq!(payload["state_topic"], "station/status/ptt_enabled");
+        assert_eq!(payload["command_topic"], "station/command");
+        assert_eq!(payload["payload_on"], r#"{"enable_ptt":true}"#);
+        assert_eq!(payload["availability_topic"], "station/status/availability");
+        assert_eq!(payload["device"]["identifiers"][0], "gb3xx");
+        assert!(payload["device"].get("manufacturer").is_none());
+        assert!(payload["device"].get("model").is_none());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::{config::Homie, event::Event, schema::Status};
+use anyhow::{anyhow, Result};
+use paho_mqtt::Message;
+
+struct Property {
+    id: &'static str,
+    name: &'static str,
+    value: fn(&Status) -> Option<bool>,
+    /// Event to set the property, if it is settable
+    set: Option<fn(bool) -> Event>,
+}
+
+struct Node {
+    id: &'static str,
+    name: &'static str,
+    properties: &'static [Property],
+}
+
+const NODES: &[Node] = &[
+    Node {
+        id: "power",
+        name: "TX power",
+        properties: &[
+            Property {
+                id: "enable",
+                name: "TX power enable",
+                value: |s| s.tx_power_enabled,
+                set: Some(Event::SetTxPowerEnable),
+            },
+            Property {
+                id: "active",
+                name: "TX power active",
+                value: |s| s.tx_power_active,
+                set: None,
+            },
+        ],
+    },
+    Node {
+        id: "ptt",
+        name: "PTT",
+        properties: &[
+            Property {
+                id: "enable",
+                name: "PTT enable",
+                value: |s| s.ptt_enabled,
+                set: Some(Event::SetPttEnable),
+            },
+            Property {
+                id: "active",
+                name: "PTT active",
+                value: |s| s.ptt_active,
+                set: None,
+            },
+        ],
+    },
+];
+
+fn retained(topic: String, payload: &str) -> Message {
+    Message::new_retained(topic, payload, 1)
+}
+
+/// Builds the retained Homie device, node and property attribute messages.
+pub(crate) fn description_messages(homie: &Homie) -> Vec<Message> {
+    let device_topic = homie.device_topic();
+
+    let mut messages = vec![
+        retained(format!("{}/$homie", device_topic), "4.0.0"),
+        retained(format!("{}/$name", device_topic), &homie.name),
+        retained(
+            format!("{}/$nodes", device_topic),
+            &NODES.iter().map(|n| n.id).collect::<Vec<_>>().join(","),
+        ),
+        retained(format!("{}/$extensions", device_topic), ""),
+    ];
+
+    for node in NODES {
+        let node_topic = format!("{}/{}", device_topic, node.id);
+        messages.push(retained(format!("{}/$name", node_topic), node.name));
+        messages.push(retained(format!("{}/$type", node_topic), "switch"));
+        messages.push(retained(
+            format!("{}/$properties", node_topic),
+            &node
+                .properties
+                .iter()
+                .map(|p| p.id)
+                .collect::<Vec<_>>()
+                .join(","),
+        ));
+
+        for property in node.properties {
+            let property_topic = format!("{}/{}", node_topic, property.id);
+            messages.push(retained(format!("{}/$name", property_topic), property.name));
+            messages.push(retained(format!("{}/$datatype", property_topic), "boolean"));
+            messages.push(retained(
+                format!("{}/$settable", property_topic),
+                &property.set.is_some().to_string(),
+            ));
+        }
+    }
+
+    messages
+}
+
+/// Topic filter matching the `/set` topics of all settable properties.
+pub(crate) fn set_topic_filter(homie: &Homie) -> String {
+    format!("{}/+/+/set", homie.device_topic())
+}
+
+/// Gives the (topic, payload) of each property that currently has a known value.
+pub(crate) fn property_values(homie: &Homie, status: &Status) -> Vec<(String, String)> {
+    let device_topic = homie.device_topic();
+    let device_topic = &device_topic;
+
+    NODES
+        .iter()
+        .flat_map(|node| {
+            node.properties.iter().filter_map(move |property| {
+                (property.value)(status).map(|value| {
+                    (
+                        format!("{}/{}/{}", device_topic, node.id, property.id),
+                        value.to_string(),
+                    )
+                })
+            })
+        })
+        .collect()
+}
+
+/// Converts a message on a property `/set` topic to the event that sets it.
+///
+/// Returns `None` if the topic is not a Homie `/set` topic of this device.
+pub(crate) fn set_event(homie: &Homie, topic: &str, payload: &str) -> Option<Result<Event>> {
+    let path = topic
+        .strip_prefix(&homie.device_topic())?
+        .strip_prefix('/')?
+        .strip_suffix("/set")?;
+    let (node_id, property_id) = path.split_once('/')?;
+
+    let set = NODES
+        .iter()
+        .find(|n| n.id == node_id)
+        .and_then(|n| n.properties.iter().find(|p| p.id == property_id))
+        .and_then(|p| p.set);
+
+    Some(match (set, payload) {
+        (None, _) => Err(anyhow!("Property \"{}\" is not settable", path)),
+        (Some(set), "true") => Ok(set(true)),
+        (Some(set), "false") => Ok(set(false)),
+        (Some(_), _) => Err(anyhow!("Invalid boolean value \"{}\"", payload)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_topics() {
+        let homie = Homie {
+            base_topic: "homie".into(),
+            device_id: "gb3xx".into(),
+            name: "GB3XX".into(),
+        };
+
+        assert_eq!(
+            Event::SetPttEnable(false),
+            set_event(&homie, "homie/gb3xx/ptt/enable/set", "false")
+                .unwrap()
+                .unwrap()
+        );
+        assert_eq!(
+            Event::SetTxPowerEnable(true),
+            set_event(&homie, "homie/gb3xx/power/enable/set", "true")
+                .unwrap()
+                .unwrap()
+        );
+        assert!(set_event(&homie, "homie/gb3xx/ptt/active/set", "true")
+            .unwrap()
+            .is_err());
+        assert!(set_event(&homie, "homie/gb3xx/ptt/enable/set", "on")
+            .unwrap()
+            .is_err());
+        assert!(set_event(&homie, "station/command", "{}").is_none());
+        assert!(set_event(&homie, "homie/gb3xx/ptt/enable", "true").is_none());
+    }
+}
@@ -0,0 +1,417 @@
+use crate::{
+    config::Http,
+    event::{Event, LocalRequest},
+    local_api,
+    metrics::METRICS,
+    schema::Response,
+};
+use anyhow::{bail, Result};
+use axum::{
+    extract::{Extension, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse, Response as HttpResponse,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use futures::{future, stream, Stream, StreamExt};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    net::TcpListener,
+    sync::broadcast::{error::RecvError, Sender},
+    task::JoinHandle,
+};
+
+/// Number of published statuses kept to be replayed to reconnecting event stream clients.
+const EVENT_HISTORY: usize = 100;
+
+/// Dashboard assets (path, content type and content), embedded so that the binary is self contained.
+const DASHBOARD: &[(&str, &str, &str)] = &[
+    (
+        "/",
+        "text/html; charset=utf-8",
+        include_str!("../dashboard/index.html"),
+    ),
+    (
+        "/dashboard.js",
+        "text/javascript; charset=utf-8",
+        include_str!("../dashboard/dashboard.js"),
+    ),
+    (
+        "/dashboard.css",
+        "text/css; charset=utf-8",
+        include_str!("../dashboard/dashboard.css"),
+    ),
+];
+
+struct AppState {
+    tx: Sender<Event>,
+    config: Http,
+    /// Most recently published statuses, oldest first
+    history: Mutex<VecDeque<Response>>,
+}
+
+type SharedState = Arc<AppState>;
+
+/// Compares fixed length digests without returning early, so that neither the token nor its length
+/// can be discovered by timing requests.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Operator that requests with a token act as (`None` if anonymous), or an error if the token is
+/// not accepted.
+fn token_operator(config: &Http, token: Option<&str>) -> Result<Option<String>> {
+    if !config.authenticated() {
+        return Ok(None);
+    }
+
+    let matches =
+        |expected: &str| token.is_some_and(|t| constant_time_eq(t.as_bytes(), expected.as_bytes()));
+    if config.token.as_ref().is_some_and(|t| matches(t.expose())) {
+        return Ok(None);
+    }
+    match config.users.iter().find(|u| matches(u.token.expose())) {
+        Some(user) => Ok(user.operator.clone()),
+        None => bail!("Invalid token"),
+    }
+}
+
+/// Operator that an authenticated request acts as.
+#[derive(Clone)]
+struct RequestOperator(Option<String>);
+
+/// Token given in the `Authorization` header, or in the `token` query parameter of an event stream
+/// request (as a browser `EventSource` cannot set headers). Query strings end up in logs, so are not
+/// accepted for anything else.
+fn request_token(request: &Request) -> Option<String> {
+    let header = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match header {
+        Some(token) => Some(token.to_string()),
+        None if request.uri().path() == "/events" => {
+            Query::<TokenQuery>::try_from_uri(request.uri())
+                .ok()
+                .and_then(|q| q.0.token)
+        }
+        None => None,
+    }
+}
+
+/// Checks the token a request is made with, noting the operator it acts as.
+async fn authenticate(
+    State(state): State<SharedState>,
+    mut request: Request,
+    next: Next,
+) -> HttpResponse {
+    match token_operator(&state.config, request_token(&request).as_deref()) {
+        Ok(operator) => {
+            request.extensions_mut().insert(RequestOperator(operator));
+            next.run(request).await
+        }
+        Err(_) => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+/// Sends a request for processing and replies with the outcome.
+async fn local_command(
+    tx: &Sender<Event>,
+    operator: Option<String>,
+    request: LocalRequest,
+) -> HttpResponse {
+    match local_api::request(tx, "http", operator, request).await {
+        Some(response) => {
+            let code = match response.error {
+                Some(_) => StatusCode::BAD_REQUEST,
+                None => StatusCode::OK,
+            };
+            (code, Json(response)).into_response()
+        }
+        None => (StatusCode::GATEWAY_TIMEOUT, "No reply to command").into_response(),
+    }
+}
+
+async fn get_status(State(state): State<SharedState>) -> HttpResponse {
+    let status = state.history.lock().unwrap().back().cloned();
+    match status {
+        Some(status) => Json(status).into_response(),
+        None => (StatusCode::SERVICE_UNAVAILABLE, "No status published yet").into_response(),
+    }
+}
+
+async fn get_metrics(State(state): State<SharedState>) -> HttpResponse {
+    if let Some(response) = state.history.lock().unwrap().back() {
+        METRICS.set_status(&response.status);
+    }
+
+    match METRICS.encode() {
+        Ok(metrics) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics).into_response(),
+        Err(e) => {
+            log::error!("Failed to encode metrics: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn post_command(
+    State(state): State<SharedState>,
+    Extension(RequestOperator(operator)): Extension<RequestOperator>,
+    body: String,
+) -> HttpResponse {
+    local_command(&state.tx, operator, LocalRequest::Command(body)).await
+}
+
+async fn post_closedown(
+    State(state): State<SharedState>,
+    Extension(RequestOperator(operator)): Extension<RequestOperator>,
+) -> HttpResponse {
+    local_command(&state.tx, operator, LocalRequest::Closedown).await
+}
+
+/// Gives the statuses to send to an event stream client that last received `last_id`.
+///
+/// New clients get the latest status, reconnecting clients get every status since the one they last
+/// received. If `last_id` is newer than any status then the controller has restarted since, and all
+/// statuses are sent.
+fn replay(history: &VecDeque<Response>, last_id: Option<u64>) -> Vec<Response> {
+    let newest = history.back().and_then(|r| r.sequence);
+
+    match last_id {
+        Some(id) if newest.is_some_and(|newest| id <= newest) => history
+            .iter()
+            .filter(|r| r.sequence > Some(id))
+            .cloned()
+            .collect(),
+        Some(_) => history.iter().cloned().collect(),
+        None => history.back().cloned().into_iter().collect(),
+    }
+}
+
+/// Streams every published status as a `status` event, identified by its sequence number.
+async fn get_events(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<sse::Event, axum::Error>>> {
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+
+    // Subscribe before taking the history so that no status is missed in between
+    let rx = state.tx.subscribe();
+    let replay = replay(&state.history.lock().unwrap(), last_id);
+    let replayed = replay.last().and_then(|r| r.sequence);
+
+    let live = stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(Event::StatusPublished(response)) => return Some((response, rx)),
+                Ok(Event::Exit) | Err(RecvError::Closed) => return None,
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => {
+                    // Each status is complete, so the next one brings the client up to date
+                    rx = rx.resubscribe();
+                }
+            }
+        }
+    })
+    .filter(move |r| future::ready(r.sequence > replayed));
+
+    Sse::new(stream::iter(replay).chain(live).map(|response| {
+        sse::Event::default()
+            .event("status")
+            .id(response.sequence.unwrap_or_default().to_string())
+            .json_data(response)
+    }))
+    .keep_alive(KeepAlive::default())
+}
+
+pub(crate) async fn run(tx: Sender<Event>, config: &Http) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind((config.bind.as_str(), config.port)).await?;
+    log::info!("HTTP API listening on {}", listener.local_addr()?);
+    if !config.authenticated() {
+        log::warn!("No HTTP API tokens set, requests will not be authenticated");
+    }
+
+    let mut rx = tx.subscribe();
+
+    let state = Arc::new(AppState {
+        tx,
+        config: config.clone(),
+        history: Mutex::new(VecDeque::with_capacity(EVENT_HISTORY)),
+    });
+
+    let mut app = Router::new()
+        .route("/status", get(get_status))
+        .route("/events", get(get_events))
+        .route("/metrics", get(get_metrics))
+        .route("/command", post(post_command))
+        .route("/closedown", post(post_closedown))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate));
+
+    // The dashboard itself holds nothing sensitive, it asks for the token to use the API
+    if config.dashboard {
+        for (path, content_type, body) in DASHBOARD {
+            app = app.route(
+                path,
+                get(|| async { ([(header::CONTENT_TYPE, *content_type)], *body) }),
+            );
+        }
+    }
+
+    let app = app.with_state(state.clone());
+
+    Ok(tokio::spawn(async move {
+        let shutdown = async move {
+            loop {
+                match rx.recv().await {
+                    Ok(Event::StatusPublished(response)) => {
+                        let mut history = state.history.lock().unwrap();
+                        if history.len() == EVENT_HISTORY {
+                            history.pop_front();
+                        }
+                        history.push_back(response);
+                    }
+                    Ok(Event::Exit) | Err(RecvError::Closed) => {
+                        log::debug!("Task exit");
+                        return;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => {
+                        // Statuses may have been missed, but each one is complete
+                        rx = rx.resubscribe();
+                    }
+                }
+            }
+        };
+
+        if let Err(e) = axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown)
+            .await
+        {
+            log::error!("HTTP server failed: {}", e);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{HttpUser, Secret},
+        schema::{ActionResult, CommandResponse, Status},
+    };
+    use tokio::{sync::broadcast, time::Duration};
+
+    #[test]
+    fn token_operators() {
+        let mut config = Http {
+            bind: "127.0.0.1".to_string(),
+            port: 8080,
+            token: None,
+            users: Vec::new(),
+            dashboard: false,
+        };
+        assert_eq!(None, token_operator(&config, None).unwrap());
+        assert_eq!(None, token_operator(&config, Some("anything")).unwrap());
+
+        config.token = Some(Secret::from("api"));
+        config.users.push(HttpUser {
+            token: Secret::from("alice's"),
+            operator: Some("alice".to_string()),
+        });
+        assert_eq!(None, token_operator(&config, Some("api")).unwrap());
+        assert_eq!(
+            Some("alice".to_string()),
+            token_operator(&config, Some("alice's")).unwrap()
+        );
+        assert!(token_operator(&config, Some("wrong")).is_err());
+        assert!(token_operator(&config, None).is_err());
+    }
+
+    #[test]
+    fn request_tokens() {
+        let request = |uri: &str, authorization: Option<&str>| {
+            let mut builder = Request::builder().uri(uri);
+            if let Some(authorization) = authorization {
+                builder = builder.header(header::AUTHORIZATION, authorization);
+            }
+            request_token(&builder.body(axum::body::Body::empty()).unwrap())
+        };
+
+        assert_eq!(
+            Some("abc".to_string()),
+            request("/command", Some("Bearer abc"))
+        );
+        assert_eq!(None, request("/command?token=abc", None));
+        assert_eq!(None, request("/closedown?token=abc", None));
+        assert_eq!(Some("abc".to_string()), request("/events?token=abc", None));
+        assert_eq!(None, request("/events", None));
+    }
+
+    #[tokio::test]
+    async fn closedown_command() {
+        let (tx, mut rx) = broadcast::channel(16);
+
+        // Act as processing, replying to the request
+        let processing = {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    if let Ok(Event::LocalCommand(request)) = rx.recv().await {
+                        assert_eq!("http", request.source);
+                        assert_eq!(Some("alice".to_string()), request.operator);
+                        assert!(matches!(request.request, LocalRequest::Closedown));
+                        let results = vec![ActionResult::dispatched(&Event::SetPttEnable(false))];
+                        let response = CommandResponse::new(None, results);
+                        tx.send(Event::LocalCommandResponse(request.id, response))
+                            .unwrap();
+                        return;
+                    }
+                }
+            })
+        };
+
+        let response = local_command(&tx, Some("alice".to_string()), LocalRequest::Closedown).await;
+        assert_eq!(StatusCode::OK, response.status());
+        processing.await.unwrap();
+    }
+
+    #[test]
+    fn event_replay() {
+        let history: VecDeque<Response> = (3..=5)
+            .map(|seq| Response::new(Status::default(), None).with_sequence(seq, Duration::ZERO))
+            .collect();
+
+        let sequences = |last_id| {
+            replay(&history, last_id)
+                .iter()
+                .map(|r| r.sequence.unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec![5], sequences(None));
+        assert_eq!(vec![4, 5], sequences(Some(3)));
+        assert_eq!(Vec::<u64>::new(), sequences(Some(5)));
+        assert_eq!(vec![3, 4, 5], sequences(Some(1)));
+        assert_eq!(vec![3, 4, 5], sequences(Some(9)));
+        assert!(replay(&VecDeque::new(), Some(2)).is_empty());
+    }
+}
@@ -1,7 +1,10 @@
-use crate::{config::IoPin, event::Event};
+use crate::{config::IoPin, event::Event, metrics::METRICS};
 use anyhow::Result;
 use sysfs_gpio::{Direction, Pin};
-use tokio::{sync::broadcast::Sender, task::JoinHandle};
+use tokio::{
+    sync::broadcast::{error::TryRecvError, Sender},
+    task::JoinHandle,
+};
 
 pub(crate) struct Input {
     pin: Pin,
@@ -28,15 +31,28 @@ impl Input {
             let mut prev: u8 = 255;
 
             loop {
-                let val = pin.get_value().unwrap();
-                if val != prev {
-                    callback(tx.clone(), val == 1);
-                    prev = val;
+                match pin.get_value() {
+                    Ok(val) if val != prev => {
+                        callback(tx.clone(), val == 1);
+                        prev = val;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::error!("Failed to read pin {}: {}", pin.get_pin(), e);
+                        METRICS.gpio_errors.inc();
+                    }
                 }
 
-                if let Ok(Event::Exit) = rx.try_recv() {
-                    log::debug!("Task exit");
-                    return;
+                loop {
+                    match rx.try_recv() {
+                        Ok(Event::Exit) | Err(TryRecvError::Closed) => {
+                            log::debug!("Task exit");
+                            return;
+                        }
+                        Ok(Event::Resync) => prev = 255,
+                        Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+                        Err(TryRecvError::Empty) => break,
+                    }
                 }
 
                 tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
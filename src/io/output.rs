@@ -2,6 +2,7 @@ use crate::config::IoPin;
 use anyhow::Result;
 use sysfs_gpio::{Direction, Pin};
 
+#[derive(Clone)]
 pub(crate) struct Output {
     pin: Pin,
     inverted: bool,
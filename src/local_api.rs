@@ -0,0 +1,42 @@
+use crate::{
+    event::{Event, LocalCommandEvent, LocalRequest},
+    schema::CommandResponse,
+};
+use tokio::{
+    sync::broadcast::{error::RecvError, Sender},
+    time::Duration,
+};
+
+/// How long to wait for a request to be replied to, longer than outputs have to confirm they have
+/// been set.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends a request from a local API for processing and waits for its reply, giving `None` if it is
+/// not replied to in time.
+pub(crate) async fn request(
+    tx: &Sender<Event>,
+    source: &'static str,
+    operator: Option<String>,
+    request: LocalRequest,
+) -> Option<CommandResponse> {
+    // Subscribe before sending so that the reply cannot be missed
+    let mut rx = tx.subscribe();
+    let event = LocalCommandEvent::new(source, operator, request);
+    let id = event.id;
+    crate::send_event!(tx, Event::LocalCommand(event));
+
+    tokio::time::timeout(REPLY_TIMEOUT, async {
+        loop {
+            match rx.recv().await {
+                Ok(Event::LocalCommandResponse(reply_id, response)) if reply_id == id => {
+                    return Some(response);
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .await
+    .ok()
+    .flatten()
+}
@@ -1,15 +1,40 @@
+mod command_ack;
+mod command_auth;
 mod config;
+mod control_socket;
 mod event;
+mod home_assistant;
+mod homie;
+mod http;
 mod io;
+mod local_api;
+mod metrics;
 mod mqtt;
 mod output_task;
 mod processing;
+mod rules;
 mod schema;
+mod stats;
+mod timed_enable;
 
-use crate::{config::Config, event::Event, io::Input};
-use anyhow::Result;
-use clap::Parser;
-use tokio::{signal, sync::broadcast};
+use crate::{
+    config::{Config, DEFAULT_CONTROL_SOCKET},
+    event::Event,
+    io::Input,
+    schema::ControlRequest,
+};
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use tokio::{
+    signal,
+    sync::broadcast,
+    task::JoinHandle,
+    time::{self, Duration},
+};
+
+/// Number of events that may be queued for a task before it starts missing them.
+const EVENT_BUS_CAPACITY: usize = 1024;
 
 #[macro_export]
 macro_rules! send_event {
@@ -20,6 +45,14 @@ macro_rules! send_event {
     };
 }
 
+/// Waits until any of the tasks has finished.
+async fn any_task_finished(tasks: &[JoinHandle<()>]) {
+    let mut interval = time::interval(Duration::from_millis(100));
+    while !tasks.iter().any(|t| t.is_finished()) {
+        interval.tick().await;
+    }
+}
+
 /// Simple tool used to kill transmission from a remote amateur radio station, gateway or repeater.
 #[derive(Clone, Debug, Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -27,6 +60,67 @@ struct Cli {
     /// Path to configuration file
     #[clap(long, env = "CONFIG_FILE", default_value = "./config.toml")]
     config_file: String,
+
+    /// Path to the control socket of a running controller, used by the subcommands
+    #[clap(long, env = "CONTROL_SOCKET", default_value = DEFAULT_CONTROL_SOCKET)]
+    socket: String,
+
+    /// Controls a running controller instead of running one
+    #[clap(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum CliCommand {
+    /// Shows the status of the station
+    Status,
+    /// Disables TX power and PTT
+    Closedown,
+    /// Enables TX power and/or PTT, both if neither is given
+    Enable {
+        #[clap(long)]
+        tx_power: bool,
+        #[clap(long)]
+        ptt: bool,
+        /// Time in milliseconds after which the enable reverts
+        #[clap(long)]
+        duration: Option<u64>,
+    },
+    /// Resets a lockout
+    ResetLockout,
+}
+
+/// Sends a subcommand to a running controller over its control socket.
+async fn client(socket: &str, command: CliCommand) -> Result<()> {
+    let request = match command {
+        CliCommand::Status => {
+            let reply = control_socket::request(socket, &ControlRequest::Status).await?;
+            control_socket::print_status(&reply);
+            return Ok(());
+        }
+        CliCommand::Closedown => ControlRequest::Closedown,
+        CliCommand::Enable {
+            tx_power,
+            ptt,
+            duration,
+        } => {
+            let both = !tx_power && !ptt;
+            let mut command = json!({ "duration": duration });
+            if tx_power || both {
+                command["enable_tx_power"] = json!(true);
+            }
+            if ptt || both {
+                command["enable_ptt"] = json!(true);
+            }
+            ControlRequest::Command { command }
+        }
+        CliCommand::ResetLockout => ControlRequest::Command {
+            command: json!({ "reset_lockout": true }),
+        },
+    };
+
+    let reply = control_socket::request(socket, &request).await?;
+    control_socket::print_command_response(&reply)
 }
 
 #[tokio::main]
@@ -36,10 +130,14 @@ async fn main() -> Result<()> {
     let args = Cli::parse();
     log::debug!("{:?}", args);
 
+    if let Some(command) = args.command {
+        return client(&args.socket, command).await;
+    }
+
     let config = Config::from_file(&args.config_file)?;
     log::debug!("{:?}", config);
 
-    let (tx, _) = broadcast::channel::<Event>(16);
+    let (tx, _) = broadcast::channel::<Event>(EVENT_BUS_CAPACITY);
 
     let mut tasks = vec![
         processing::run(tx.clone(), config.clone())?,
@@ -47,26 +145,63 @@ async fn main() -> Result<()> {
         output_task::run(tx.clone(), &config)?,
     ];
 
-    if let Some(c) = config.tx_power_status {
-        tasks.push(Input::new(&c)?.watch(tx.clone(), |tx, state| {
+    if let Some(ref c) = config.http {
+        tasks.push(http::run(tx.clone(), c).await?);
+    }
+
+    if let Some(ref c) = config.control_socket {
+        tasks.push(control_socket::run(tx.clone(), c)?);
+    }
+
+    if let Some(ref c) = config.tx_power_status {
+        tasks.push(Input::new(c)?.watch(tx.clone(), |tx, state| {
             crate::send_event!(tx, Event::TxPowerStateChanged(state));
         })?);
     }
 
-    if let Some(c) = config.ptt_status {
-        tasks.push(Input::new(&c)?.watch(tx.clone(), |tx, state| {
+    if let Some(ref c) = config.ptt_status {
+        tasks.push(Input::new(c)?.watch(tx.clone(), |tx, state| {
             crate::send_event!(tx, Event::PttStateChanged(state));
         })?);
     }
 
+    for interlock in &config.interlocks {
+        let name = interlock.name.clone();
+        tasks.push(
+            Input::new(&interlock.pin)?.watch(tx.clone(), move |tx, state| {
+                crate::send_event!(tx, Event::InterlockStateChanged(name.clone(), state));
+            })?,
+        );
+    }
+
+    for input in &config.inputs {
+        let name = input.name.clone();
+        tasks.push(Input::new(&input.pin)?.watch(tx.clone(), move |tx, state| {
+            crate::send_event!(tx, Event::InputStateChanged(name.clone(), state));
+        })?);
+    }
+
     send_event!(tx, Event::SetTxPowerEnable(false));
     send_event!(tx, Event::SetPttEnable(false));
 
-    match signal::ctrl_c().await {
-        Ok(()) => {}
-        Err(err) => {
-            log::error!("Unable to listen for shutdown signal: {}", err);
+    let fault = tokio::select! {
+        result = signal::ctrl_c() => {
+            if let Err(err) = result {
+                log::error!("Unable to listen for shutdown signal: {}", err);
+            }
+            false
         }
+        _ = any_task_finished(&tasks) => true,
+    };
+
+    if fault {
+        // Without every task running the station can no longer be safely controlled
+        log::error!("A task has stopped unexpectedly, closing down");
+        send_event!(tx, Event::SendAlarm("Station controller fault".to_string()));
+        send_event!(tx, Event::SetTxPowerEnable(false));
+        send_event!(tx, Event::SetPttEnable(false));
+        time::sleep(Duration::from_millis(500)).await;
+        output_task::force_closedown(&config);
     }
 
     log::info! {"Terminating..."};
@@ -77,5 +212,8 @@ async fn main() -> Result<()> {
         }
     }
 
-    Ok(())
+    match fault {
+        true => Err(anyhow!("Task stopped unexpectedly")),
+        false => Ok(()),
+    }
 }
@@ -0,0 +1,210 @@
+use crate::schema::Status;
+use prometheus::{
+    Gauge, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+
+/// Metrics of the controller, exposed by the HTTP API.
+pub(crate) static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("metrics should be valid"));
+
+pub(crate) struct Metrics {
+    registry: Registry,
+
+    tx_power_enabled: Gauge,
+    tx_power_active: Gauge,
+    ptt_enabled: Gauge,
+    ptt_active: Gauge,
+    tx_power_enable_remaining: Gauge,
+    ptt_enable_remaining: Gauge,
+    tx_guard_remaining: Gauge,
+    interlocks_tripped: Gauge,
+    interlock_tripped: GaugeVec,
+    input_active: GaugeVec,
+    lockout: Gauge,
+    mqtt_connected: Gauge,
+
+    pub key_ups: IntCounter,
+    pub tx_guard_trips: IntCounter,
+    pub commands: IntCounter,
+    pub rejected_commands: IntCounter,
+    pub gpio_errors: IntCounter,
+    pub mqtt_reconnects: IntCounterVec,
+
+    pub transmission_length: Histogram,
+    pub command_latency: Histogram,
+}
+
+/// Value of a gauge for an optional status value, `NaN` if it is not known.
+fn known<T: Into<f64>>(value: Option<T>) -> f64 {
+    value.map(Into::into).unwrap_or(f64::NAN)
+}
+
+fn bool_value(value: Option<bool>) -> f64 {
+    known(value.map(u8::from))
+}
+
+fn seconds(ms: Option<u64>) -> f64 {
+    known(ms.map(|ms| ms as f64 / 1000.0))
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("remote_closedown".to_string()), None)?;
+
+        macro_rules! register {
+            ($metric:expr) => {{
+                let metric = $metric;
+                registry.register(Box::new(metric.clone()))?;
+                metric
+            }};
+        }
+
+        let gauge = |name: &str, help: &str| -> prometheus::Result<Gauge> {
+            let gauge = Gauge::new(name, help)?;
+            gauge.set(f64::NAN);
+            Ok(gauge)
+        };
+
+        Ok(Self {
+            tx_power_enabled: register!(gauge("tx_power_enabled", "TX power enable state")?),
+            tx_power_active: register!(gauge("tx_power_active", "TX power state")?),
+            ptt_enabled: register!(gauge("ptt_enabled", "PTT enable state")?),
+            ptt_active: register!(gauge("ptt_active", "PTT state")?),
+            tx_power_enable_remaining: register!(gauge(
+                "tx_power_enable_remaining_seconds",
+                "Time until a timed TX power enable reverts"
+            )?),
+            ptt_enable_remaining: register!(gauge(
+                "ptt_enable_remaining_seconds",
+                "Time until a timed PTT enable reverts"
+            )?),
+            tx_guard_remaining: register!(gauge(
+                "tx_guard_remaining_seconds",
+                "Time until the TX guard trips"
+            )?),
+            interlocks_tripped: register!(gauge(
+                "interlocks_tripped",
+                "Number of interlocks tripped"
+            )?),
+            interlock_tripped: register!(GaugeVec::new(
+                Opts::new("interlock_tripped", "Tripped interlocks"),
+                &["interlock"]
+            )?),
+            input_active: register!(GaugeVec::new(
+                Opts::new("input_active", "Input state"),
+                &["input"]
+            )?),
+            lockout: register!(gauge("lockout", "Whether the station is locked out")?),
+            mqtt_connected: register!(gauge("mqtt_connected", "Whether a broker is connected")?),
+
+            key_ups: register!(IntCounter::new("key_ups_total", "Transmissions started")?),
+            tx_guard_trips: register!(IntCounter::new(
+                "tx_guard_trips_total",
+                "Transmissions cut off by the TX guard"
+            )?),
+            commands: register!(IntCounter::new(
+                "commands_total",
+                "Commands received, including rejected commands"
+            )?),
+            rejected_commands: register!(IntCounter::new(
+                "rejected_commands_total",
+                "Commands rejected as invalid or unauthenticated"
+            )?),
+            gpio_errors: register!(IntCounter::new(
+                "gpio_errors_total",
+                "Failures to read an input or set an output"
+            )?),
+            mqtt_reconnects: register!(IntCounterVec::new(
+                Opts::new("mqtt_reconnects_total", "Reconnections to a broker"),
+                &["broker"]
+            )?),
+
+            transmission_length: register!(Histogram::with_opts(
+                HistogramOpts::new("transmission_length_seconds", "Length of transmissions")
+                    .buckets(vec![
+                        1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0
+                    ])
+            )?),
+            command_latency: register!(Histogram::with_opts(
+                HistogramOpts::new(
+                    "command_latency_seconds",
+                    "Time from a command being accepted to an output being set"
+                )
+                .buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0])
+            )?),
+
+            registry,
+        })
+    }
+
+    /// Updates the status gauges.
+    pub(crate) fn set_status(&self, status: &Status) {
+        self.tx_power_enabled
+            .set(bool_value(status.tx_power_enabled));
+        self.tx_power_active.set(bool_value(status.tx_power_active));
+        self.ptt_enabled.set(bool_value(status.ptt_enabled));
+        self.ptt_active.set(bool_value(status.ptt_active));
+        self.tx_power_enable_remaining
+            .set(seconds(status.tx_power_enable_remaining_ms));
+        self.ptt_enable_remaining
+            .set(seconds(status.ptt_enable_remaining_ms));
+        self.tx_guard_remaining
+            .set(seconds(status.tx_guard_remaining_ms));
+
+        self.interlocks_tripped
+            .set(status.interlocks_tripped.len() as f64);
+        self.interlock_tripped.reset();
+        for interlock in &status.interlocks_tripped {
+            self.interlock_tripped
+                .with_label_values(&[interlock])
+                .set(1.0);
+        }
+
+        for (input, active) in &status.inputs {
+            self.input_active
+                .with_label_values(&[input])
+                .set(bool_value(Some(*active)));
+        }
+
+        self.lockout.set(bool_value(Some(status.lockout.is_some())));
+        self.mqtt_connected
+            .set(bool_value(Some(status.mqtt_broker.is_some())));
+    }
+
+    /// Gives the metrics in the Prometheus text format.
+    pub(crate) fn encode(&self) -> prometheus::Result<String> {
+        TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_gauges() {
+        let metrics = Metrics::new().unwrap();
+        metrics.set_status(&Status {
+            ptt_active: Some(true),
+            tx_guard_remaining_ms: Some(1500),
+            interlocks_tripped: ["high_swr".to_string()].into(),
+            inputs: [("mains_fail".to_string(), false)].into(),
+            ..Default::default()
+        });
+
+        let text = metrics.encode().unwrap();
+        for line in [
+            "remote_closedown_ptt_active 1",
+            "remote_closedown_ptt_enabled NaN",
+            "remote_closedown_tx_guard_remaining_seconds 1.5",
+            "remote_closedown_interlocks_tripped 1",
+            "remote_closedown_interlock_tripped{interlock=\"high_swr\"} 1",
+            "remote_closedown_input_active{input=\"mains_fail\"} 0",
+            "remote_closedown_lockout 0",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing \"{}\"", line);
+        }
+    }
+}
@@ -1,100 +1,520 @@
 use crate::{
-    config::Mqtt,
+    config::{Broker, Mqtt, Tls},
     event::{Event, MqttMessageEvent},
+    home_assistant, homie,
+    metrics::METRICS,
     schema::{Response, Status},
 };
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use paho_mqtt::{
-    AsyncClient, ConnectOptionsBuilder, CreateOptionsBuilder, Message, PersistenceType,
+    AsyncClient, ConnectOptions, ConnectOptionsBuilder, CreateOptionsBuilder, Message,
+    PersistenceType, SslOptions, SslOptionsBuilder, MQTT_VERSION_3_1_1, MQTT_VERSION_5,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    fs,
+    hash::{Hash, Hasher},
 };
 use tokio::{
-    sync::broadcast::Sender,
+    sync::{
+        broadcast::{error::RecvError, Sender},
+        mpsc::{self, UnboundedSender},
+    },
     task::JoinHandle,
-    time::{self, Duration},
+    time::{Duration, Instant},
 };
 
-pub(crate) async fn run(tx: Sender<Event>, config: &Mqtt) -> Result<JoinHandle<()>> {
-    let mut client = AsyncClient::new(
+/// Publishes that take longer than this to be acknowledged by the broker (the broker round trip
+/// time) are warned about.
+const SLOW_PUBLISH_TIME: Duration = Duration::from_secs(1);
+
+/// Time between attempts to connect to a broker that could not be connected to.
+const CONNECT_RETRY_TIME: Duration = Duration::from_secs(5);
+
+/// Identical commands received from different brokers within this time are only acted on once.
+const COMMAND_DEDUP_TIME: Duration = Duration::from_secs(5);
+
+enum BrokerEvent {
+    Connected,
+    ConnectionLost,
+    Message(Message),
+}
+
+/// Checks that a PEM file can be read, so that a bad path gives a clear error rather than a
+/// generic TLS failure when connecting.
+fn check_pem_file(description: &str, filename: &str) -> Result<()> {
+    let contents = fs::read_to_string(filename)
+        .with_context(|| format!("Failed to load {} \"{}\"", description, filename))?;
+
+    if !contents.contains("-----BEGIN ") {
+        bail!(
+            "Failed to load {} \"{}\": file is not PEM encoded",
+            description,
+            filename
+        );
+    }
+
+    Ok(())
+}
+
+fn build_ssl_options(config: &Tls) -> Result<SslOptions> {
+    let mut builder = SslOptionsBuilder::new();
+
+    if let Some(ref ca_file) = config.ca_file {
+        check_pem_file("CA certificate", ca_file)?;
+        builder.trust_store(ca_file)?;
+    }
+
+    if let Some(ref ca_path) = config.ca_path {
+        builder.ca_path(ca_path)?;
+    }
+
+    if let Some(ref client_cert) = config.client_cert {
+        check_pem_file("client certificate", client_cert)?;
+        builder.key_store(client_cert)?;
+    }
+
+    if let Some(ref client_key) = config.client_key {
+        check_pem_file("client key", client_key)?;
+        builder.private_key(client_key)?;
+    }
+
+    if let Some(ref password) = config.client_key_password {
+        builder.private_key_password(password.expose());
+    }
+
+    if !config.alpn.is_empty() {
+        builder.alpn_protos(&config.alpn.iter().map(|s| s.as_str()).collect::<Vec<_>>());
+    }
+
+    builder
+        .enable_server_cert_auth(config.verify_server_cert)
+        .verify(config.verify_hostname);
+
+    Ok(builder.finalize())
+}
+
+fn build_connect_options(
+    config: &Mqtt,
+    broker: &Broker,
+    mqtt_version: u32,
+) -> Result<ConnectOptions> {
+    let mut builder = ConnectOptionsBuilder::with_mqtt_version(mqtt_version);
+
+    if mqtt_version >= MQTT_VERSION_5 {
+        builder.clean_start(true);
+    } else {
+        builder.clean_session(true);
+    }
+
+    if let Some(ref tls) = broker.tls {
+        if broker.uri.starts_with("tcp://") || broker.uri.starts_with("mqtt://") {
+            log::warn!("TLS is configured but the broker URI does not use TLS");
+        }
+        builder.ssl_options(build_ssl_options(tls)?);
+    }
+
+    Ok(builder
+        .automatic_reconnect(Duration::from_secs(1), Duration::from_secs(5))
+        .keep_alive_interval(Duration::from_secs(5))
+        .user_name(&broker.username)
+        .password(broker.password.expose())
+        .will_message(match config.availability_enabled() {
+            true => Message::new_retained(
+                config.availability_topic(),
+                config.availability_payloads().1,
+                1,
+            ),
+            false => Message::new(
+                config.status_topic.as_str(),
+                serde_json::to_string(&Response::new(
+                    Status::default(),
+                    Some("Station controller has gone offline".to_string()),
+                ))?,
+                0,
+            ),
+        })
+        .finalize())
+}
+
+/// Queues a message for publishing without waiting for it to be delivered, delivery (and the broker
+/// round trip time, from queueing until the broker acknowledges it) is logged by a separate task.
+fn publish(client: &AsyncClient, msg: MqttMessageEvent) {
+    let topic = msg.topic().to_string();
+    let start = Instant::now();
+    let delivery_token = client.publish(msg.into());
+
+    tokio::spawn(async move {
+        match delivery_token.await {
+            Ok(()) => {
+                let round_trip = start.elapsed();
+                if round_trip > SLOW_PUBLISH_TIME {
+                    log::warn!(
+                        "Broker took {}ms to acknowledge message to \"{}\"",
+                        round_trip.as_millis(),
+                        topic
+                    );
+                } else {
+                    log::debug!(
+                        "Broker acknowledged message to \"{}\" in {}ms",
+                        topic,
+                        round_trip.as_millis()
+                    );
+                }
+            }
+            Err(e) => log::error!("Error sending message to \"{}\": {}", topic, e),
+        }
+    });
+}
+
+/// Makes the initial connection to a broker, retrying until it succeeds (after which the client
+/// reconnects automatically).
+async fn connect(client: AsyncClient, uri: String, options: Vec<ConnectOptions>) {
+    loop {
+        for options in &options {
+            match client.connect(options.clone()).await {
+                Ok(response) => {
+                    log::info!(
+                        "Connected to \"{}\" using MQTT version {}",
+                        uri,
+                        response.connect_response().unwrap().mqtt_version
+                    );
+                    return;
+                }
+                Err(e) => log::warn!(
+                    "Failed to connect to \"{}\" using MQTT version {} ({})",
+                    uri,
+                    options.mqtt_version(),
+                    e
+                ),
+            }
+        }
+
+        tokio::time::sleep(CONNECT_RETRY_TIME).await;
+    }
+}
+
+fn create_client(
+    config: &Mqtt,
+    broker: &Broker,
+    idx: usize,
+    broker_tx: UnboundedSender<(usize, BrokerEvent)>,
+) -> Result<AsyncClient> {
+    let client = AsyncClient::new(
         CreateOptionsBuilder::new()
-            .server_uri(&config.broker)
+            .server_uri(&broker.uri)
             .client_id(&config.client_id)
+            // The client must be created for MQTT v5 to connect with it (as paho does by default,
+            // but this must not change), it can still connect using v3.1.1
+            .mqtt_version(MQTT_VERSION_5)
             .persistence(PersistenceType::None)
             .finalize(),
     )?;
 
-    let stream = client.get_stream(25);
+    let command_topic = config.command_topic.clone();
+    let extra_command_topics = config.extra_command_topics.clone();
+    let operator_subtopics = config.operator_subtopics;
+    let availability = config.availability_enabled().then(|| {
+        (
+            config.availability_topic(),
+            config.availability_payloads().0,
+        )
+    });
+    let homie = config.homie.clone();
+    let discovery = config
+        .home_assistant
+        .as_ref()
+        .map(|ha| home_assistant::discovery_messages(config, ha))
+        .transpose()?
+        .unwrap_or_default();
 
     {
-        let command_topic = config.command_topic.clone();
-        let tx = tx.clone();
-
+        let broker_tx = broker_tx.clone();
         client.set_connected_callback(move |c| {
-            log::info!("Connected to broker");
-
             c.subscribe(command_topic.clone(), 2);
+            if operator_subtopics {
+                c.subscribe(format!("{}/+", command_topic), 2);
+            }
+            for topic in &extra_command_topics {
+                c.subscribe(topic.as_str(), 2);
+            }
 
-            crate::send_event!(
-                tx,
-                Event::SendStatus(Some("Station controller is now online".to_string()))
-            );
+            if let Some(ref homie) = homie {
+                c.publish(Message::new_retained(
+                    format!("{}/$state", homie.device_topic()),
+                    "init",
+                    1,
+                ));
+                for msg in homie::description_messages(homie) {
+                    c.publish(msg);
+                }
+                c.subscribe(homie::set_topic_filter(homie), 2);
+            }
+
+            if let Some((ref topic, online)) = availability {
+                c.publish(Message::new_retained(topic.as_str(), online, 1));
+            }
+
+            for msg in &discovery {
+                c.publish(msg.clone());
+            }
+
+            let _ = broker_tx.send((idx, BrokerEvent::Connected));
         });
     }
 
-    let response = client
-        .connect(
-            ConnectOptionsBuilder::new()
-                .clean_session(true)
-                .automatic_reconnect(Duration::from_secs(1), Duration::from_secs(5))
-                .keep_alive_interval(Duration::from_secs(5))
-                .user_name(&config.username)
-                .password(&config.password)
-                .will_message(Message::new(
-                    config.status_topic.as_str(),
-                    serde_json::to_string(&Response::new(
-                        Status::default(),
-                        Some("Station controller has gone offline".to_string()),
-                    ))?,
-                    0,
-                ))
-                .finalize(),
-        )
-        .wait()?;
+    {
+        let broker_tx = broker_tx.clone();
+        client.set_connection_lost_callback(move |_| {
+            let _ = broker_tx.send((idx, BrokerEvent::ConnectionLost));
+        });
+    }
 
-    log::info!(
-        "Using MQTT version {}",
-        response.connect_response().unwrap().mqtt_version
-    );
+    client.set_message_callback(move |_, msg| {
+        if let Some(msg) = msg {
+            let _ = broker_tx.send((idx, BrokerEvent::Message(msg)));
+        }
+    });
+
+    Ok(client)
+}
+
+/// Filters out commands that have already been received from a different broker.
+///
+/// Each copy of a command received from one broker is matched with at most one copy from each other
+/// broker, so a command repeated on the same broker (e.g. a retried closedown) is still acted on.
+#[derive(Default)]
+struct CommandDedup {
+    /// Time, hash and the brokers that a command has been received from
+    recent: VecDeque<(Instant, u64, Vec<usize>)>,
+}
+
+impl CommandDedup {
+    fn is_duplicate(&mut self, broker: usize, msg: &Message) -> bool {
+        let mut hasher = DefaultHasher::new();
+        msg.topic().hash(&mut hasher);
+        msg.payload().hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let now = Instant::now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|(t, _, _)| now - *t > COMMAND_DEDUP_TIME)
+        {
+            self.recent.pop_front();
+        }
+
+        match self
+            .recent
+            .iter_mut()
+            .find(|(_, h, brokers)| *h == hash && !brokers.contains(&broker))
+        {
+            Some((_, _, brokers)) => {
+                brokers.push(broker);
+                true
+            }
+            None => {
+                self.recent.push_back((now, hash, vec![broker]));
+                false
+            }
+        }
+    }
+}
+
+/// The broker to send messages via, the first connected one.
+fn preferred_broker(connected: &[bool]) -> Option<usize> {
+    connected.iter().position(|c| *c)
+}
+
+pub(crate) async fn run(tx: Sender<Event>, config: &Mqtt) -> Result<JoinHandle<()>> {
+    let brokers = config.brokers();
+    let (broker_tx, mut broker_rx) = mpsc::unbounded_channel();
+
+    let mut clients = Vec::new();
+    for (idx, broker) in brokers.iter().enumerate() {
+        let options = vec![
+            build_connect_options(config, broker, MQTT_VERSION_5)?,
+            build_connect_options(config, broker, MQTT_VERSION_3_1_1)?,
+        ];
+        let client = create_client(config, broker, idx, broker_tx.clone())?;
+        tokio::spawn(connect(client.clone(), broker.uri.clone(), options));
+        clients.push(client);
+    }
+
+    // Published on a clean exit, as the last will is only sent when the connection is lost
+    let disconnected_message = match config.homie {
+        Some(ref homie) => Some(Message::new_retained(
+            format!("{}/$state", homie.device_topic()),
+            "disconnected",
+            1,
+        )),
+        None => config.availability_enabled().then(|| {
+            Message::new_retained(
+                config.availability_topic(),
+                config.availability_payloads().1,
+                1,
+            )
+        }),
+    };
 
     let mut rx = tx.subscribe();
+    let config = config.clone();
 
     Ok(tokio::spawn(async move {
-        let mut beat = time::interval(Duration::from_millis(100));
+        let mut connected = vec![false; clients.len()];
+        let mut ever_connected = vec![false; clients.len()];
+        // The most preferred connected broker, which messages are sent to
+        let mut active: Option<usize> = None;
+        let mut dedup = CommandDedup::default();
 
         loop {
-            if let Ok(event) = rx.try_recv() {
-                match event {
-                    Event::Exit => {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Ok(Event::Exit) => {
+                        if let Some(msg) = disconnected_message {
+                            for (client, _) in clients.iter().zip(&connected).filter(|(_, c)| **c) {
+                                if let Err(e) = client.publish(msg.clone()).await {
+                                    log::error!("Error sending disconnected message: {}", e);
+                                }
+                            }
+                        }
                         log::debug!("Task exit");
                         return;
                     }
-                    Event::MqttMessageSend(msg) => match client.try_publish(msg.into()) {
-                        Ok(delivery_token) => {
-                            if let Err(e) = delivery_token.wait() {
-                                log::error!("Error sending message: {}", e);
+                    Ok(Event::MqttMessageSend(msg)) => match active {
+                        Some(idx) => publish(&clients[idx], msg),
+                        None => log::warn!(
+                            "Not connected to a broker, dropping message to \"{}\"",
+                            msg.topic()
+                        ),
+                    },
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => {
+                        // Messages may have been missed, make sure the latest status is published
+                        log::error!("Missed {} events", n);
+                        rx = rx.resubscribe();
+                        crate::send_event!(tx, Event::SendStatus(None));
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                Some((idx, event)) = broker_rx.recv() => {
+                    match event {
+                        BrokerEvent::Connected => {
+                            log::info!(
+                                "Connected to broker \"{}\" using MQTT version {}",
+                                brokers[idx].uri,
+                                clients[idx].mqtt_version()
+                            );
+                            connected[idx] = true;
+                            if ever_connected[idx] {
+                                METRICS
+                                    .mqtt_reconnects
+                                    .with_label_values(&[&brokers[idx].uri])
+                                    .inc();
                             }
+                            ever_connected[idx] = true;
                         }
-                        Err(e) => log::error!("Error creating/queuing the message: {}", e),
-                    },
-                    _ => {}
-                }
-            }
+                        BrokerEvent::ConnectionLost => {
+                            log::warn!("Connection to broker \"{}\" lost", brokers[idx].uri);
+                            connected[idx] = false;
+                        }
+                        BrokerEvent::Message(msg) => {
+                            log::info!(
+                                "Received message on topic \"{}\" from \"{}\"",
+                                msg.topic(),
+                                brokers[idx].uri
+                            );
+                            if config.is_own_topic(msg.topic()) {
+                                // Matched by a broad extra command topic filter
+                                log::debug!("Ignoring own message on \"{}\"", msg.topic());
+                            } else if dedup.is_duplicate(idx, &msg) {
+                                log::info!("Ignoring command already received from another broker");
+                            } else {
+                                crate::send_event!(
+                                    tx,
+                                    Event::MqttMessageReceive(MqttMessageEvent::from(msg))
+                                );
+                            }
+                        }
+                    }
 
-            if let Ok(Some(msg)) = stream.try_recv() {
-                log::info! {"Received message on topic \"{}\"", msg.topic()};
-                crate::send_event!(tx, Event::MqttMessageReceive(MqttMessageEvent::from(msg)));
+                    let preferred = preferred_broker(&connected);
+                    if preferred != active {
+                        active = preferred;
+                        match active {
+                            Some(idx) => {
+                                log::info!("Sending messages via \"{}\"", brokers[idx].uri);
+                                crate::send_event!(
+                                    tx,
+                                    Event::MqttConnected(brokers[idx].uri.clone())
+                                );
+                            }
+                            None => {
+                                log::warn!("Not connected to any broker");
+                                crate::send_event!(tx, Event::MqttDisconnected);
+                            }
+                        }
+                    }
+                },
             }
-
-            beat.tick().await;
         }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_dedup() {
+        let mut dedup = CommandDedup::default();
+        let command = Message::new("station/command", "{\"enable_ptt\":false}", 1);
+        let other = Message::new("station/command", "{\"enable_ptt\":true}", 1);
+
+        assert!(!dedup.is_duplicate(0, &command));
+        assert!(dedup.is_duplicate(1, &command));
+        assert!(!dedup.is_duplicate(0, &other));
+
+        // Repeated on the same broker, then its copy from the other broker
+        assert!(!dedup.is_duplicate(0, &command));
+        assert!(dedup.is_duplicate(1, &command));
+
+        // Repeated on the other broker, with no copy from the first yet
+        assert!(!dedup.is_duplicate(1, &command));
+        assert!(dedup.is_duplicate(0, &command));
+    }
+
+    #[tokio::test]
+    async fn client_created_for_mqtt_v5() {
+        let config = Mqtt {
+            client_id: "test".to_string(),
+            status_topic: "station/status".to_string(),
+            ..Default::default()
+        };
+        // Nothing listens on port 1, so only the connection itself should fail
+        let broker = Broker {
+            uri: "tcp://127.0.0.1:1".to_string(),
+            ..Default::default()
+        };
+        let (broker_tx, _broker_rx) = mpsc::unbounded_channel();
+        let client = create_client(&config, &broker, 0, broker_tx).unwrap();
+
+        for version in [MQTT_VERSION_5, MQTT_VERSION_3_1_1] {
+            let options = build_connect_options(&config, &broker, version).unwrap();
+            let error = client.connect(options).await.unwrap_err().to_string();
+            assert!(!error.contains("another version of MQTT"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn failover_and_failback() {
+        assert_eq!(None, preferred_broker(&[false, false]));
+        assert_eq!(Some(0), preferred_broker(&[true, true]));
+        // Primary lost
+        assert_eq!(Some(1), preferred_broker(&[false, true]));
+        // Primary back
+        assert_eq!(Some(0), preferred_broker(&[true, false]));
+        assert_eq!(Some(0), preferred_broker(&[true, true]));
+    }
+}
@@ -2,9 +2,14 @@ use crate::{
     config::{Config, IoPin},
     event::Event,
     io::Output,
+    metrics::METRICS,
 };
 use anyhow::Result;
-use tokio::{sync::broadcast::Sender, task::JoinHandle};
+use tokio::{
+    sync::broadcast::{error::RecvError, Sender},
+    task::JoinHandle,
+    time::Duration,
+};
 
 fn output_or_none(config: &Option<IoPin>) -> Result<Option<Output>> {
     Ok(match config {
@@ -13,14 +18,96 @@ fn output_or_none(config: &Option<IoPin>) -> Result<Option<Output>> {
     })
 }
 
+/// Sets an enable output, reporting the outcome with the given events.
+fn set_enable(
+    tx: &Sender<Event>,
+    name: &str,
+    output: &Output,
+    state: bool,
+    changed: fn(bool) -> Event,
+    failed: fn(String) -> Event,
+) -> Option<bool> {
+    match output.set(state) {
+        Ok(_) => {
+            crate::send_event!(tx, changed(state));
+            Some(state)
+        }
+        Err(e) => {
+            log::error!("Failed to set {}: {}", name, e);
+            METRICS.gpio_errors.inc();
+            crate::send_event!(tx, failed(e.to_string()));
+            None
+        }
+    }
+}
+
+/// Disables TX power and PTT directly, for use when the output task can no longer be relied upon.
+pub(crate) fn force_closedown(config: &Config) {
+    for output in [&config.tx_power_enable, &config.ptt_enable]
+        .into_iter()
+        .flatten()
+    {
+        if let Err(e) = Output::new(output).and_then(|o| o.set(false)) {
+            log::error!("Failed to force closedown of pin {}: {}", output.number, e);
+            METRICS.gpio_errors.inc();
+        }
+    }
+}
+
 pub(crate) fn run(tx: Sender<Event>, config: &Config) -> Result<JoinHandle<()>> {
     let mut rx = tx.subscribe();
 
     let tx_power_enable_output = output_or_none(&config.tx_power_enable)?;
     let ptt_enable_output = output_or_none(&config.ptt_enable)?;
+    let tx_guard_warning_output = output_or_none(&config.tx_guard_warning_output)?;
+    let tx_guard_warning_pulse_time = config
+        .tx_guard_warning_pulse_time
+        .unwrap_or(Duration::from_millis(250));
 
     Ok(tokio::spawn(async move {
-        while let Ok(event) = rx.recv().await {
+        let mut tx_power_enable_state = None;
+        let mut ptt_enable_state = None;
+
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(n)) => {
+                    // A closedown may have been missed, so close down to be safe
+                    log::error!("Missed {} events, closing down", n);
+                    // Skip the backlog rather than fall behind further
+                    rx = rx.resubscribe();
+                    if let Some(ref output) = tx_power_enable_output {
+                        tx_power_enable_state = set_enable(
+                            &tx,
+                            "TX power enable",
+                            output,
+                            false,
+                            Event::TxPowerEnableStateChanged,
+                            Event::TxPowerEnableFailed,
+                        );
+                    }
+                    if let Some(ref output) = ptt_enable_output {
+                        ptt_enable_state = set_enable(
+                            &tx,
+                            "PTT enable",
+                            output,
+                            false,
+                            Event::PttEnableStateChanged,
+                            Event::PttEnableFailed,
+                        );
+                    }
+                    crate::send_event!(
+                        tx,
+                        Event::SendAlarm(format!(
+                            "Output control missed {} events, closed down as a precaution",
+                            n
+                        ))
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
             match event {
                 Event::Exit => {
                     log::debug!("Task exit");
@@ -29,39 +116,51 @@ pub(crate) fn run(tx: Sender<Event>, config: &Config) -> Result<JoinHandle<()>>
                 Event::SetTxPowerEnable(state) => {
                     log::info!("Request setting TX power enable to {}", state);
                     if let Some(ref output) = tx_power_enable_output {
-                        match output.set(state) {
-                            Ok(_) => {
-                                crate::send_event!(tx, Event::TxPowerEnableStateChanged(state));
-                            }
-                            Err(e) => {
-                                log::error!("Failed to set TX power enable: {}", e);
-                                crate::send_event!(
-                                    tx,
-                                    Event::SendStatus(Some(
-                                        "Failed to set TX power enable".to_string(),
-                                    ))
-                                );
-                            }
-                        }
+                        tx_power_enable_state = set_enable(
+                            &tx,
+                            "TX power enable",
+                            output,
+                            state,
+                            Event::TxPowerEnableStateChanged,
+                            Event::TxPowerEnableFailed,
+                        );
                     }
                 }
                 Event::SetPttEnable(state) => {
                     log::info!("Request setting PTT enable to {}", state);
                     if let Some(ref output) = ptt_enable_output {
-                        match output.set(state) {
-                            Ok(_) => {
-                                crate::send_event!(tx, Event::PttEnableStateChanged(state));
+                        ptt_enable_state = set_enable(
+                            &tx,
+                            "PTT enable",
+                            output,
+                            state,
+                            Event::PttEnableStateChanged,
+                            Event::PttEnableFailed,
+                        );
+                    }
+                }
+                Event::Resync => {
+                    if let Some(state) = tx_power_enable_state {
+                        crate::send_event!(tx, Event::TxPowerEnableStateChanged(state));
+                    }
+                    if let Some(state) = ptt_enable_state {
+                        crate::send_event!(tx, Event::PttEnableStateChanged(state));
+                    }
+                }
+                Event::TxGuardWarning(_) => {
+                    if let Some(ref output) = tx_guard_warning_output {
+                        let output = output.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = output.set(true) {
+                                log::error!("Failed to set TX guard warning output: {}", e);
+                                METRICS.gpio_errors.inc();
                             }
-                            Err(e) => {
-                                log::error!("Failed to set PTT enable: {}", e);
-                                crate::send_event!(
-                                    tx,
-                                    Event::SendStatus(
-                                        Some("Failed to set PTT enable".to_string(),)
-                                    )
-                                );
+                            tokio::time::sleep(tx_guard_warning_pulse_time).await;
+                            if let Err(e) = output.set(false) {
+                                log::error!("Failed to clear TX guard warning output: {}", e);
+                                METRICS.gpio_errors.inc();
                             }
-                        }
+                        });
                     }
                 }
                 _ => {}
@@ -69,3 +168,34 @@ pub(crate) fn run(tx: Sender<Event>, config: &Config) -> Result<JoinHandle<()>>
         }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    #[tokio::test]
+    async fn closedown_after_lag() {
+        let (tx, _) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), &Config::default()).unwrap();
+        tokio::task::yield_now().await;
+
+        // Overflow the bus before the task gets to run
+        for _ in 0..20 {
+            tx.send(Event::SetPttEnable(true)).unwrap();
+        }
+
+        let mut rx = tx.subscribe();
+        tokio::task::yield_now().await;
+        assert_eq!(
+            Event::SendAlarm(
+                "Output control missed 4 events, closed down as a precaution".to_string()
+            ),
+            rx.try_recv().unwrap()
+        );
+        assert!(rx.try_recv().is_err());
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+}
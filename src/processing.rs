@@ -1,85 +1,863 @@
 use crate::{
+    command_ack::{CommandAcks, Reply},
+    command_auth::{AuthenticatedCommand, CommandVerifier},
     config::Config,
-    event::{Event, MqttMessageEvent},
-    schema::{Command, Response, Status},
+    event::{Event, LocalCommandEvent, LocalRequest, MqttMessageEvent},
+    homie,
+    rules::Rules,
+    schema::{ActionResult, Output, Response, StatsResponse, Status},
+    stats::Stats,
+    timed_enable::TimedEnable,
 };
-use anyhow::Result;
-use tokio::{sync::broadcast::Sender, task::JoinHandle};
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, VecDeque};
+use tokio::{
+    sync::broadcast::{
+        error::{RecvError, TryRecvError},
+        Receiver, Sender,
+    },
+    task::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Starts or stops the closedown failure timer depending on whether PTT is still sensed as active
+/// after PTT enable has been removed.
+fn update_closedown_failure_task(
+    tx: &Sender<Event>,
+    config: &Config,
+    status: &Status,
+    task: &mut Option<JoinHandle<()>>,
+) {
+    if let Some(closedown_failure_time) = config.closedown_failure_time {
+        let failed = status.ptt_enabled == Some(false) && status.ptt_active == Some(true);
+
+        if !failed {
+            if let Some(task) = task.take() {
+                if task.is_finished() {
+                    crate::send_event!(
+                        tx,
+                        Event::ClearAlarm(
+                            "Closedown failure cleared, PTT no longer active".to_string()
+                        )
+                    );
+                }
+                task.abort();
+            }
+        } else if task.is_none() {
+            let tx = tx.clone();
+            *task = Some(tokio::spawn(async move {
+                tokio::time::sleep(closedown_failure_time).await;
+                crate::send_event!(tx, Event::SetTxPowerEnable(false));
+                crate::send_event!(
+                    tx,
+                    Event::SendAlarm(format!(
+                        "Closedown failed, PTT still active {}ms after PTT enable was removed",
+                        closedown_failure_time.as_millis()
+                    ))
+                );
+            }));
+        }
+    }
+}
+
+/// Gives the reason that enabling TX power or PTT is currently not permitted, if any.
+fn enable_refusal_reason(status: &Status) -> Option<String> {
+    if let Some(ref reason) = status.lockout {
+        Some(format!("locked out: {}", reason))
+    } else if !status.interlocks_tripped.is_empty() {
+        Some(format!(
+            "interlocks tripped: {}",
+            status
+                .interlocks_tripped
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    } else {
+        None
+    }
+}
+
+/// Where to reply to a command: the MQTT v5 response topic, the `response_topic` field of the command
+/// or the default response topic, in that order of preference.
+fn command_response_topic<'a>(
+    config: &'a Config,
+    request: &'a MqttMessageEvent,
+    command_response_topic: Option<&'a str>,
+) -> Option<&'a str> {
+    request
+        .response_topic
+        .as_deref()
+        .or(command_response_topic)
+        .or(config.mqtt.response_topic.as_deref())
+}
+
+/// Records an audit log entry, also publishing it to the log topic if there is one.
+fn audit(tx: &Sender<Event>, config: &Config, level: log::Level, msg: String) {
+    log::log!(target: "audit", level, "{}", msg);
+
+    if let Some(ref log_topic) = config.mqtt.log_topic {
+        crate::send_event!(
+            tx,
+            Event::MqttMessageSend(MqttMessageEvent::new(log_topic, &msg))
+        );
+    }
+}
+
+/// Sends the events requested by a command, refusing any that the operator does not have permission
+/// for or enables that are not currently permitted.
+fn dispatch_command_events(
+    tx: &Sender<Event>,
+    config: &Config,
+    status: &Status,
+    verifier: &CommandVerifier,
+    operator: Option<&str>,
+    events: Vec<Event>,
+) -> Vec<ActionResult> {
+    let mut results = Vec::new();
+    for event in events {
+        if !verifier.permitted(operator, &event) {
+            let reason = format!(
+                "operator \"{}\" does not have permission",
+                operator.unwrap_or("anonymous")
+            );
+            let msg = format!("Denied {}, {}", ActionResult::action_name(&event), reason);
+            audit(tx, config, log::Level::Warn, msg.clone());
+            crate::send_event!(tx, Event::SendStatus(Some(msg)));
+            results.push(ActionResult::denied(&event, reason));
+            continue;
+        }
+
+        match (&event, enable_refusal_reason(status)) {
+            (Event::SetTxPowerEnable(true) | Event::SetPttEnable(true), Some(reason)) => {
+                let msg = format!("Refusing to enable, {}", reason);
+                log::warn!("{}", msg);
+                crate::send_event!(tx, Event::SendStatus(Some(msg)));
+                results.push(ActionResult::refused(&event, reason));
+            }
+            (
+                Event::SetTxPowerEnableTimeout(Some(_)) | Event::SetPttEnableTimeout(Some(_)),
+                Some(reason),
+            ) => {
+                results.push(ActionResult::refused(&event, reason));
+            }
+            (Event::ResetLockout, _) if status.lockout.is_none() => {
+                results.push(ActionResult::ignored(&event, "not locked out".to_string()));
+            }
+            _ => {
+                let configured = match event {
+                    Event::SetTxPowerEnable(_) => config.tx_power_enable.is_some(),
+                    Event::SetPttEnable(_) => config.ptt_enable.is_some(),
+                    _ => true,
+                };
+                // Without an output there is nothing to confirm that the action has been applied
+                results.push(match configured {
+                    true => ActionResult::dispatched(&event),
+                    false => ActionResult::ignored(&event, "no output configured".to_string()),
+                });
+                crate::send_event!(tx, event);
+            }
+        }
+    }
+    results
+}
+
+/// Where a command was received from.
+enum CommandSource<'a> {
+    Mqtt(&'a MqttMessageEvent),
+    /// A local API request and its command message
+    Local(&'a LocalCommandEvent, &'a str),
+}
+
+impl CommandSource<'_> {
+    /// Topic or local API the command was received on
+    fn origin(&self) -> &str {
+        match self {
+            CommandSource::Mqtt(request) => request.topic(),
+            CommandSource::Local(request, _) => request.source,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            CommandSource::Mqtt(request) => &request.message,
+            CommandSource::Local(_, message) => message,
+        }
+    }
+
+    /// Where to reply to the command, `response_topic` is the `response_topic` field of the command.
+    fn reply(&self, config: &Config, response_topic: Option<&str>) -> Option<Reply> {
+        match self {
+            CommandSource::Mqtt(request) => command_response_topic(config, request, response_topic)
+                .map(|topic| Reply::mqtt(topic, request)),
+            CommandSource::Local(request, _) => Some(Reply::Local(request.id)),
+        }
+    }
+
+    fn authenticate(
+        &self,
+        config: &Config,
+        verifier: &mut CommandVerifier,
+    ) -> Result<AuthenticatedCommand> {
+        match self {
+            CommandSource::Mqtt(request) => {
+                let command = verifier.verify(self.message())?;
+                verifier.identify(command, request, &config.mqtt.command_topic)
+            }
+            CommandSource::Local(request, message) => {
+                // The local API has authenticated any operator it gives (by peer user or token)
+                let command = match request.operator {
+                    Some(_) => verifier.verify_from_operator(message)?,
+                    None => verifier.verify(message)?,
+                };
+                Ok(AuthenticatedCommand {
+                    operator: verifier.resolve_operator(
+                        command.operator.clone(),
+                        &[request.operator.as_deref()],
+                    )?,
+                    ..command
+                })
+            }
+        }
+    }
+}
+
+/// Authenticates a command, sends the events it requests and arranges for it to be replied to.
+fn handle_command(
+    tx: &Sender<Event>,
+    config: &Config,
+    status: &Status,
+    verifier: &mut CommandVerifier,
+    stats: &mut Stats,
+    acks: &mut CommandAcks,
+    source: CommandSource,
+) {
+    match source.authenticate(config, verifier) {
+        Ok(AuthenticatedCommand {
+            operator,
+            command: cmd,
+        }) => {
+            log::debug!("Got command message: {:?}", cmd);
+            if let Some(ref operator) = operator {
+                audit(
+                    tx,
+                    config,
+                    log::Level::Info,
+                    format!(
+                        "Accepted command from operator \"{}\" on \"{}\": {}",
+                        operator,
+                        source.origin(),
+                        source.message()
+                    ),
+                );
+            }
+            stats.command_received(true);
+
+            let results = dispatch_command_events(
+                tx,
+                config,
+                status,
+                verifier,
+                operator.as_deref(),
+                cmd.generate_events(),
+            );
+
+            acks.add(
+                tx,
+                source.reply(config, cmd.response_topic.as_deref()),
+                cmd.request_id.clone(),
+                results,
+            );
+        }
+        Err(e) => {
+            audit(
+                tx,
+                config,
+                log::Level::Warn,
+                format!(
+                    "Rejected command on \"{}\" ({}): {}",
+                    source.origin(),
+                    e,
+                    source.message()
+                ),
+            );
+            stats.command_received(false);
+
+            crate::send_event!(
+                tx,
+                Event::SendStatus(Some(format!("Rejected command: {}", e)))
+            );
+
+            // Recover what is possible from the command to know where to reply to
+            let mut value =
+                serde_json::from_str::<serde_json::Value>(source.message()).unwrap_or_default();
+            if let Some(payload) = value["payload"].as_str() {
+                value = serde_json::from_str(payload).unwrap_or_default();
+            }
+            acks.reject(
+                tx,
+                source.reply(config, value["response_topic"].as_str()),
+                value["request_id"].as_str().map(|s| s.to_string()),
+                e.to_string(),
+            );
+        }
+    }
+}
+
+/// Sends an event at a fixed interval, starting one interval from now.
+fn periodic_task(tx: &Sender<Event>, period: Duration, event: Event) -> JoinHandle<()> {
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            crate::send_event!(tx, event.clone());
+        }
+    })
+}
+
+/// Takes the events still queued after the event bus has lagged, skipping the state reports (and
+/// status requests) that resynchronising gives again. Everything else, such as commands, lockouts
+/// and the TX guard, can't be given again so is kept.
+fn queued_events(rx: &mut Receiver<Event>) -> VecDeque<Event> {
+    let mut events = VecDeque::new();
+    loop {
+        match rx.try_recv() {
+            Ok(
+                Event::TxPowerEnableStateChanged(_)
+                | Event::TxPowerStateChanged(_)
+                | Event::PttEnableStateChanged(_)
+                | Event::PttStateChanged(_)
+                | Event::InterlockStateChanged(_, _)
+                | Event::InputStateChanged(_, _)
+                | Event::SendStatus(None)
+                | Event::Resync,
+            ) => {}
+            Ok(event) => events.push_back(event),
+            Err(TryRecvError::Lagged(n)) => log::error!("Missed {} more events", n),
+            Err(TryRecvError::Empty | TryRecvError::Closed) => return events,
+        }
+    }
+}
 
 pub(crate) fn run(tx: Sender<Event>, config: Config) -> Result<JoinHandle<()>> {
     let mut rx = tx.subscribe();
+    let mut command_verifier = CommandVerifier::new(&config)?;
 
     Ok(tokio::spawn(async move {
         let mut status = Status::default();
+        let mut published_retained = HashMap::new();
 
         let mut tx_guard_timeout_task: Option<JoinHandle<()>> = None;
+        let mut tx_guard_deadline: Option<Instant> = None;
+        let mut closedown_failure_task: Option<JoinHandle<()>> = None;
+
+        let mut rules = Rules::new(&config.rules);
+
+        let mut command_acks = CommandAcks::default();
+
+        let mut tx_power_timed_enable =
+            TimedEnable::new("TX power", Event::SetTxPowerEnable(false));
+        let mut ptt_timed_enable = TimedEnable::new("PTT", Event::SetPttEnable(false));
+
+        let mut stats = Stats::default();
+        let stats_task = config
+            .stats_interval
+            .map(|interval| periodic_task(&tx, interval, Event::SendStats));
+
+        let startup = Instant::now();
+        let mut status_sequence = 0;
+        let status_task = config
+            .status_interval
+            .map(|interval| periodic_task(&tx, interval, Event::SendStatus(None)));
+
+        // Events queued when the event bus lagged, handled before any further events
+        let mut backlog = VecDeque::new();
+
+        loop {
+            let event = match backlog.pop_front() {
+                Some(event) => Ok(event),
+                None => rx.recv().await,
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(RecvError::Lagged(n)) => {
+                    // State changes may have been missed, have them reported again
+                    log::error!("Missed {} events, resynchronising", n);
+                    // Skip state reports in the backlog rather than fall behind further
+                    backlog = queued_events(&mut rx);
+                    crate::send_event!(tx, Event::Resync);
+                    crate::send_event!(
+                        tx,
+                        Event::SendStatus(Some(format!("Missed {} events, resynchronising", n)))
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
 
-        while let Ok(event) = rx.recv().await {
             match event {
                 Event::Exit => {
                     log::debug!("Task exit");
+                    for task in [stats_task, status_task].into_iter().flatten() {
+                        task.abort();
+                    }
                     return;
                 }
+                Event::MqttConnected(broker) => {
+                    // Retained status values may be stale (or not on this broker), publish them all
+                    // again
+                    status.mqtt_broker = Some(broker);
+                    published_retained.clear();
+                    crate::send_event!(
+                        tx,
+                        Event::SendStatus(Some("Station controller is now online".to_string()))
+                    );
+                }
                 Event::MqttMessageReceive(event) => {
-                    match serde_json::from_str::<Command>(&event.message) {
-                        Ok(cmd) => {
-                            log::debug!("Got command message: {:?}", cmd);
-                            for cmd_event in cmd.generate_events() {
-                                crate::send_event!(tx, cmd_event);
+                    if let Some(set) =
+                        config.mqtt.homie.as_ref().and_then(|homie| {
+                            homie::set_event(homie, event.topic(), &event.message)
+                        })
+                    {
+                        // Homie set messages carry no signature, so can't be authenticated
+                        let signing_required =
+                            config.command_signing.as_ref().is_some_and(|s| s.required);
+                        let set = set.and_then(|set| match signing_required {
+                            true => Err(anyhow!("Homie set messages can't be signed")),
+                            false => Ok(set),
+                        });
+                        match set {
+                            Ok(set) => {
+                                log::debug!("Got Homie set message: {:?}", set);
+                                stats.command_received(true);
+                                let results = dispatch_command_events(
+                                    &tx,
+                                    &config,
+                                    &status,
+                                    &command_verifier,
+                                    event
+                                        .operator
+                                        .as_deref()
+                                        .filter(|_| config.mqtt.trust_operator_claims),
+                                    vec![set],
+                                );
+                                // Not replied to, but tracked for command latency
+                                command_acks.add(&tx, None, None, results);
+                            }
+                            Err(e) => {
+                                log::error!("Invalid Homie set message: {}", e);
+                                stats.command_received(false);
+                                crate::send_event!(
+                                    tx,
+                                    Event::SendStatus(Some(format!("Rejected command: {}", e)))
+                                );
+                            }
+                        }
+                        continue;
+                    }
+
+                    handle_command(
+                        &tx,
+                        &config,
+                        &status,
+                        &mut command_verifier,
+                        &mut stats,
+                        &mut command_acks,
+                        CommandSource::Mqtt(&event),
+                    );
+                }
+                Event::LocalCommand(local) => match local.request {
+                    LocalRequest::Command(ref message) => {
+                        handle_command(
+                            &tx,
+                            &config,
+                            &status,
+                            &mut command_verifier,
+                            &mut stats,
+                            &mut command_acks,
+                            CommandSource::Local(&local, message),
+                        );
+                    }
+                    LocalRequest::Closedown => {
+                        let reply = Some(Reply::Local(local.id));
+                        match command_verifier.resolve_operator(None, &[local.operator.as_deref()])
+                        {
+                            Ok(operator) => {
+                                audit(
+                                    &tx,
+                                    &config,
+                                    log::Level::Info,
+                                    format!(
+                                        "Closedown requested via {} by operator \"{}\"",
+                                        local.source,
+                                        operator.as_deref().unwrap_or("anonymous")
+                                    ),
+                                );
+                                stats.command_received(true);
+
+                                let results = dispatch_command_events(
+                                    &tx,
+                                    &config,
+                                    &status,
+                                    &command_verifier,
+                                    operator.as_deref(),
+                                    vec![
+                                        Event::SetTxPowerEnable(false),
+                                        Event::SetPttEnable(false),
+                                    ],
+                                );
+                                command_acks.add(&tx, reply, None, results);
+                            }
+                            Err(e) => {
+                                audit(
+                                    &tx,
+                                    &config,
+                                    log::Level::Warn,
+                                    format!("Rejected closedown via {} ({})", local.source, e),
+                                );
+                                stats.command_received(false);
+                                command_acks.reject(&tx, reply, None, e.to_string());
                             }
                         }
-                        Err(e) => log::error!("Failed to parse command message: {}", e),
                     }
+                },
+                Event::MqttDisconnected => {
+                    status.mqtt_broker = None;
+                }
+                Event::CommandAckTimeout(id) => {
+                    command_acks.timeout(&tx, id);
+                }
+                Event::TxPowerEnableFailed(reason) => {
+                    command_acks.confirm(&tx, Output::TxPowerEnable, Err(reason));
+                    crate::send_event!(
+                        tx,
+                        Event::SendStatus(Some("Failed to set TX power enable".to_string()))
+                    );
+                }
+                Event::PttEnableFailed(reason) => {
+                    command_acks.confirm(&tx, Output::PttEnable, Err(reason));
+                    crate::send_event!(
+                        tx,
+                        Event::SendStatus(Some("Failed to set PTT enable".to_string()))
+                    );
+                }
+                // An untimed enable replaces a timed one, the timeout of a timed enable follows
+                Event::SetTxPowerEnable(true) => tx_power_timed_enable.cancel(),
+                Event::SetPttEnable(true) => ptt_timed_enable.cancel(),
+                Event::SetTxPowerEnableTimeout(duration) => {
+                    match duration {
+                        Some(duration) => tx_power_timed_enable.start(&tx, duration),
+                        None => tx_power_timed_enable.cancel(),
+                    }
+                    crate::send_event!(tx, Event::SendStatus(None));
+                }
+                Event::SetPttEnableTimeout(duration) => {
+                    match duration {
+                        Some(duration) => ptt_timed_enable.start(&tx, duration),
+                        None => ptt_timed_enable.cancel(),
+                    }
+                    crate::send_event!(tx, Event::SendStatus(None));
+                }
+                Event::ExtendEnableTimeouts(duration) => {
+                    tx_power_timed_enable.extend(&tx, duration);
+                    ptt_timed_enable.extend(&tx, duration);
+                    crate::send_event!(tx, Event::SendStatus(None));
                 }
                 Event::TxPowerEnableStateChanged(state) => {
+                    command_acks.confirm(&tx, Output::TxPowerEnable, Ok(state));
                     status.tx_power_enabled = Some(state);
+                    if !state {
+                        tx_power_timed_enable.cancel();
+                    }
                     crate::send_event!(tx, Event::SendStatus(None));
+                    rules.evaluate(&tx, &status);
+
+                    if state && enable_refusal_reason(&status).is_some() {
+                        crate::send_event!(tx, Event::SetTxPowerEnable(false));
+                    }
                 }
                 Event::TxPowerStateChanged(state) => {
                     status.tx_power_active = Some(state);
                     crate::send_event!(tx, Event::SendStatus(None));
+                    rules.evaluate(&tx, &status);
                 }
                 Event::PttEnableStateChanged(state) => {
+                    command_acks.confirm(&tx, Output::PttEnable, Ok(state));
                     status.ptt_enabled = Some(state);
+                    if !state {
+                        ptt_timed_enable.cancel();
+                    }
                     crate::send_event!(tx, Event::SendStatus(None));
+                    rules.evaluate(&tx, &status);
+
+                    if state && enable_refusal_reason(&status).is_some() {
+                        crate::send_event!(tx, Event::SetPttEnable(false));
+                    }
+
+                    update_closedown_failure_task(
+                        &tx,
+                        &config,
+                        &status,
+                        &mut closedown_failure_task,
+                    );
                 }
                 Event::PttStateChanged(state) => {
+                    let already_active = status.ptt_active == Some(true);
                     status.ptt_active = Some(state);
                     crate::send_event!(tx, Event::SendStatus(None));
+                    rules.evaluate(&tx, &status);
+                    stats.ptt_state_changed(state);
 
-                    if let Some(tx_guard_time) = config.tx_guard_time {
+                    update_closedown_failure_task(
+                        &tx,
+                        &config,
+                        &status,
+                        &mut closedown_failure_task,
+                    );
+
+                    // PTT being reported as still active (e.g. on resync) must not restart a
+                    // running guard
+                    let guard_running = already_active && tx_guard_deadline.is_some();
+                    if let Some(tx_guard_time) =
+                        config.tx_guard_time.filter(|_| !(state && guard_running))
+                    {
                         if let Some(task) = tx_guard_timeout_task {
                             task.abort();
                         }
 
                         if state {
                             let tx = tx.clone();
+                            let mut warnings: Vec<Duration> = config
+                                .tx_guard_warnings
+                                .iter()
+                                .filter(|w| **w < tx_guard_time)
+                                .cloned()
+                                .collect();
+                            warnings.sort_by(|a, b| b.cmp(a));
+
+                            let start = Instant::now();
+                            tx_guard_deadline = Some(start + tx_guard_time);
+
                             tx_guard_timeout_task = Some(tokio::spawn(async move {
-                                tokio::time::sleep(tx_guard_time).await;
-                                crate::send_event!(tx, Event::SetTxPowerEnable(false));
-                                crate::send_event!(tx, Event::SetPttEnable(false));
-                                crate::send_event!(
-                                    tx,
-                                    Event::SendStatus(Some(format!(
-                                        "TX timed out after {}ms",
-                                        tx_guard_time.as_millis()
-                                    )))
-                                );
+                                for remaining in warnings {
+                                    tokio::time::sleep_until(start + tx_guard_time - remaining)
+                                        .await;
+                                    crate::send_event!(tx, Event::TxGuardWarning(remaining));
+                                }
+
+                                tokio::time::sleep_until(start + tx_guard_time).await;
+                                crate::send_event!(tx, Event::TxGuardTripped);
                             }));
                         } else {
                             tx_guard_timeout_task = None;
+                            tx_guard_deadline = None;
+                        }
+                    }
+                }
+                Event::TxGuardWarning(remaining) => {
+                    crate::send_event!(
+                        tx,
+                        Event::SendStatus(Some(format!(
+                            "TX will time out in {}ms",
+                            remaining.as_millis()
+                        )))
+                    );
+                }
+                Event::TxGuardTripped => {
+                    stats.tx_guard_tripped();
+                    tx_guard_deadline = None;
+                    crate::send_event!(tx, Event::SetTxPowerEnable(false));
+                    crate::send_event!(tx, Event::SetPttEnable(false));
+                    crate::send_event!(
+                        tx,
+                        Event::SendStatus(Some(format!(
+                            "TX timed out after {}ms",
+                            config.tx_guard_time.unwrap_or_default().as_millis()
+                        )))
+                    );
+                }
+                Event::InterlockStateChanged(name, state) => {
+                    if state {
+                        if status.interlocks_tripped.insert(name.clone()) {
+                            log::warn!("Interlock \"{}\" tripped", name);
+                            crate::send_event!(tx, Event::SetTxPowerEnable(false));
+                            crate::send_event!(tx, Event::SetPttEnable(false));
+                            crate::send_event!(
+                                tx,
+                                Event::SendStatus(Some(format!("Interlock \"{}\" tripped", name)))
+                            );
                         }
+                    } else if status.interlocks_tripped.remove(&name) {
+                        log::info!("Interlock \"{}\" cleared", name);
+                        crate::send_event!(
+                            tx,
+                            Event::SendStatus(Some(format!("Interlock \"{}\" cleared", name)))
+                        );
+                    }
+                    rules.evaluate(&tx, &status);
+                }
+                Event::InputStateChanged(name, state) => {
+                    status.inputs.insert(name, state);
+                    crate::send_event!(tx, Event::SendStatus(None));
+                    rules.evaluate(&tx, &status);
+                }
+                Event::RuleEnable(rule, output) => match enable_refusal_reason(&status) {
+                    Some(reason) => {
+                        let msg = format!("Refusing to enable for rule \"{}\", {}", rule, reason);
+                        log::warn!("{}", msg);
+                        crate::send_event!(tx, Event::SendStatus(Some(msg)));
                     }
+                    None => crate::send_event!(
+                        tx,
+                        match output {
+                            Output::TxPowerEnable => Event::SetTxPowerEnable(true),
+                            Output::PttEnable => Event::SetPttEnable(true),
+                        }
+                    ),
+                },
+                Event::Lockout(reason) => {
+                    log::warn!("Entering lockout: {}", reason);
+                    status.lockout = Some(reason.clone());
+                    crate::send_event!(tx, Event::SetTxPowerEnable(false));
+                    crate::send_event!(tx, Event::SetPttEnable(false));
+                    crate::send_event!(
+                        tx,
+                        Event::SendStatus(Some(format!("Locked out: {}", reason)))
+                    );
+                    rules.evaluate(&tx, &status);
+                }
+                Event::ResetLockout if status.lockout.is_some() => {
+                    log::info!("Lockout reset");
+                    status.lockout = None;
+                    crate::send_event!(tx, Event::SendStatus(Some("Lockout reset".to_string())));
+                    rules.evaluate(&tx, &status);
                 }
                 Event::SendStatus(msg) => {
-                    if let Err(e) = || -> Result<usize> {
-                        Ok(tx.send(Event::MqttMessageSend(MqttMessageEvent::new(
-                            &config.mqtt.status_topic,
-                            &serde_json::to_string(&Response::new(status.clone(), msg))?,
-                        )))?)
-                    }() {
-                        log::error!("Failed building/sending status message: {}", e);
+                    status.tx_power_enable_remaining_ms = tx_power_timed_enable
+                        .remaining()
+                        .map(|d| d.as_millis() as u64);
+                    status.ptt_enable_remaining_ms =
+                        ptt_timed_enable.remaining().map(|d| d.as_millis() as u64);
+                    status.tx_guard_remaining_ms = tx_guard_deadline
+                        .map(|d| d.saturating_duration_since(Instant::now()).as_millis() as u64);
+
+                    status_sequence += 1;
+                    let uptime = startup.elapsed();
+
+                    let mut retained = Vec::new();
+                    if config.mqtt.status_format.fields() {
+                        retained.extend(status.fields().into_iter().map(|(field, payload)| {
+                            (config.mqtt.status_field_topic(&field), payload)
+                        }));
+                        retained.push((
+                            config.mqtt.status_field_topic("sequence"),
+                            status_sequence.to_string(),
+                        ));
+                        retained.push((
+                            config.mqtt.status_field_topic("uptime_ms"),
+                            uptime.as_millis().to_string(),
+                        ));
+                    }
+                    if let Some(ref homie) = config.mqtt.homie {
+                        retained.extend(homie::property_values(homie, &status));
+                    }
+                    for (topic, payload) in retained {
+                        if published_retained.get(&topic) != Some(&payload) {
+                            crate::send_event!(
+                                tx,
+                                Event::MqttMessageSend(MqttMessageEvent::new_retained(
+                                    &topic, &payload
+                                ))
+                            );
+                            published_retained.insert(topic, payload);
+                        }
+                    }
+
+                    if config.mqtt.status_format.fields() {
+                        if let Some(ref msg) = msg {
+                            crate::send_event!(
+                                tx,
+                                Event::MqttMessageSend(MqttMessageEvent::new(
+                                    &config.mqtt.status_field_topic("message"),
+                                    msg
+                                ))
+                            );
+                        }
+                    }
+
+                    let response =
+                        Response::new(status.clone(), msg).with_sequence(status_sequence, uptime);
+
+                    if config.mqtt.status_format.json() {
+                        if let Err(e) = || -> Result<usize> {
+                            Ok(tx.send(Event::MqttMessageSend(MqttMessageEvent::new(
+                                &config.mqtt.status_topic,
+                                &serde_json::to_string(&response)?,
+                            )))?)
+                        }() {
+                            log::error!("Failed building/sending status message: {}", e);
+                        }
                     }
+
+                    if config.local_api_enabled() {
+                        crate::send_event!(tx, Event::StatusPublished(response));
+                    }
+                }
+                Event::SendStats => {
+                    stats.roll_day();
+
+                    if let Some(ref stats_topic) = config.mqtt.stats_topic {
+                        match serde_json::to_string(&StatsResponse::new(stats.clone())) {
+                            Ok(payload) => crate::send_event!(
+                                tx,
+                                Event::MqttMessageSend(MqttMessageEvent::new(
+                                    stats_topic,
+                                    &payload
+                                ))
+                            ),
+                            Err(e) => log::error!("Failed building stats message: {}", e),
+                        }
+                    }
+                }
+                Event::ClearAlarm(msg) => {
+                    log::info!("Alarm cleared: {}", msg);
+
+                    // An empty retained message removes the alarm from the broker
+                    if let (Some(ref alarm_topic), true) =
+                        (&config.mqtt.alarm_topic, config.mqtt.alarm_retain)
+                    {
+                        crate::send_event!(
+                            tx,
+                            Event::MqttMessageSend(MqttMessageEvent::new_retained(alarm_topic, ""))
+                        );
+                    }
+
+                    crate::send_event!(tx, Event::SendStatus(Some(msg)));
+                }
+                Event::SendAlarm(msg) => {
+                    log::error!("Alarm: {}", msg);
+
+                    if let Some(ref alarm_topic) = config.mqtt.alarm_topic {
+                        if let Err(e) = || -> Result<usize> {
+                            let payload = serde_json::to_string(&Response::new(
+                                status.clone(),
+                                Some(msg.clone()),
+                            ))?;
+                            Ok(
+                                tx.send(Event::MqttMessageSend(match config.mqtt.alarm_retain {
+                                    true => MqttMessageEvent::new_retained(alarm_topic, &payload),
+                                    false => MqttMessageEvent::new(alarm_topic, &payload),
+                                }))?,
+                            )
+                        }() {
+                            log::error!("Failed building/sending alarm message: {}", e);
+                        }
+                    }
+
+                    crate::send_event!(tx, Event::SendStatus(Some(msg)));
                 }
                 _ => {}
             }
@@ -88,9 +866,17 @@ pub(crate) fn run(tx: Sender<Event>, config: Config) -> Result<JoinHandle<()>> {
 }
 
 #[cfg(test)]
+#[allow(clippy::field_reassign_with_default)]
 mod tests {
     use super::*;
-    use tokio::{sync::broadcast, time::Duration};
+    use crate::{
+        config::{
+            CommandSigning, Homie, IoPin, Operator, Permission, Rule, RuleAction, StatusFormat,
+        },
+        rules::Expression,
+        schema::{ActionOutcome, CommandOutcome},
+    };
+    use tokio::sync::broadcast;
 
     macro_rules! wait_millis {
         ($n: expr) => {
@@ -136,6 +922,7 @@ mod tests {
 
     macro_rules! expect_tx_guard_closedown {
         ($rx: expr) => {
+            assert_eq!(Event::TxGuardTripped, $rx.try_recv().unwrap());
             assert_eq!(Event::SetTxPowerEnable(false), $rx.try_recv().unwrap());
             assert_eq!(Event::SetPttEnable(false), $rx.try_recv().unwrap());
             assert_eq!(
@@ -149,6 +936,15 @@ mod tests {
         };
     }
 
+    /// Configuration with enable outputs, so that commands wait for them to be set.
+    fn config_with_outputs() -> Config {
+        Config {
+            tx_power_enable: Some(IoPin::default()),
+            ptt_enable: Some(IoPin::default()),
+            ..Default::default()
+        }
+    }
+
     #[tokio::test]
     async fn set_ptt_mqtt_command() {
         let config = Config::default();
@@ -210,6 +1006,41 @@ mod tests {
         task.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn tx_guard_remaining() {
+        let mut config = Config::default();
+        config.tx_guard_time = Some(Duration::from_millis(5000));
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_tx_on!(tx, rx);
+
+        // The time until the guard trips is given in the status
+        wait_millis!(200);
+        send_event_receive_it_and_yield!(tx, rx, Event::SendStatus(None));
+        let status: serde_json::Value = match rx.try_recv().unwrap() {
+            Event::MqttMessageSend(msg) => serde_json::from_str(&msg.message).unwrap(),
+            _ => panic!("Expected status message"),
+        };
+        let remaining = status["status"]["tx_guard_remaining_ms"].as_u64().unwrap();
+        assert!(remaining > 3000 && remaining < 5000);
+
+        // PTT reported as active again (e.g. on resync) doesn't restart the guard
+        send_tx_on!(tx, rx);
+        send_event_receive_it_and_yield!(tx, rx, Event::SendStatus(None));
+        let status: serde_json::Value = match rx.try_recv().unwrap() {
+            Event::MqttMessageSend(msg) => serde_json::from_str(&msg.message).unwrap(),
+            _ => panic!("Expected status message"),
+        };
+        assert!(status["status"]["tx_guard_remaining_ms"].as_u64().unwrap() <= remaining);
+
+        send_tx_off!(tx, rx);
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
     #[tokio::test]
     async fn tx_guard_extensive() {
         let mut config = Config::default();
@@ -252,4 +1083,1295 @@ mod tests {
         tx.send(Event::Exit).unwrap();
         task.await.unwrap();
     }
+
+    macro_rules! expect_mqtt_message {
+        ($rx: expr) => {
+            assert!(match $rx.try_recv().unwrap() {
+                Event::MqttMessageSend(_) => true,
+                _ => false,
+            });
+        };
+    }
+
+    #[tokio::test]
+    async fn closedown_failure_alarm() {
+        let mut config = Config {
+            closedown_failure_time: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        config.mqtt.alarm_topic = Some("alarm".to_string());
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(tx, rx, Event::PttEnableStateChanged(false));
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+
+        send_tx_on!(tx, rx);
+
+        // PTT enable has only just been removed, give the closedown some time to take effect.
+        wait_millis!(450);
+        expect_no_event!(rx);
+
+        // PTT is still active, the closedown has failed.
+        wait_millis!(100);
+        let msg = "Closedown failed, PTT still active 500ms after PTT enable was removed";
+        assert_eq!(Event::SetTxPowerEnable(false), rx.try_recv().unwrap());
+        assert_eq!(Event::SendAlarm(msg.to_string()), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+        assert_eq!(
+            Event::SendStatus(Some(msg.to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closedown_failure_alarm_cleared() {
+        let mut config = Config {
+            closedown_failure_time: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        config.mqtt.alarm_topic = Some("alarm".to_string());
+        config.mqtt.alarm_retain = true;
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(tx, rx, Event::PttEnableStateChanged(false));
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+
+        send_tx_on!(tx, rx);
+
+        wait_millis!(300);
+        assert_eq!(Event::SetTxPowerEnable(false), rx.try_recv().unwrap());
+        assert!(matches!(rx.try_recv().unwrap(), Event::SendAlarm(_)));
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Event::MqttMessageSend(msg) if msg.topic() == "alarm" && !msg.message.is_empty()
+        ));
+        assert!(matches!(rx.try_recv().unwrap(), Event::SendStatus(Some(_))));
+        expect_mqtt_message!(rx);
+
+        // PTT finally drops, the retained alarm is removed
+        send_event_receive_it_and_yield!(tx, rx, Event::PttStateChanged(false));
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        let msg = "Closedown failure cleared, PTT no longer active";
+        assert_eq!(Event::ClearAlarm(msg.to_string()), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+        assert_eq!(
+            Event::MqttMessageSend(MqttMessageEvent::new_retained("alarm", "")),
+            rx.try_recv().unwrap()
+        );
+        assert_eq!(
+            Event::SendStatus(Some(msg.to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closedown_failure_recovers() {
+        let config = Config {
+            closedown_failure_time: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_tx_on!(tx, rx);
+
+        send_event_receive_it_and_yield!(tx, rx, Event::PttEnableStateChanged(false));
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+
+        // PTT drops within the grace period, the closedown worked.
+        wait_millis!(300);
+        send_tx_off!(tx, rx);
+
+        wait_millis!(500);
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn interlock_forces_closedown_and_refuses_enable() {
+        let config = Config::default();
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        // Interlock reported as clear at startup, nothing to do.
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::InterlockStateChanged("high_swr".to_string(), false)
+        );
+        expect_no_event!(rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::InterlockStateChanged("high_swr".to_string(), true)
+        );
+        assert_eq!(Event::SetTxPowerEnable(false), rx.try_recv().unwrap());
+        assert_eq!(Event::SetPttEnable(false), rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SendStatus(Some("Interlock \"high_swr\" tripped".to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "",
+                "{\"enable_ptt\":true, \"enable_tx_power\":false}",
+            ))
+        );
+        assert_eq!(Event::SetTxPowerEnable(false), rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SendStatus(Some(
+                "Refusing to enable, interlocks tripped: high_swr".to_string()
+            )),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::InterlockStateChanged("high_swr".to_string(), false)
+        );
+        assert_eq!(
+            Event::SendStatus(Some("Interlock \"high_swr\" cleared".to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new("", "{\"enable_ptt\":true}",))
+        );
+        assert_eq!(Event::SetPttEnable(true), rx.try_recv().unwrap());
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rule_enable_refused() {
+        let config = Config {
+            rules: vec![Rule {
+                name: "carrier".to_string(),
+                condition: Expression::try_from("input.carrier".to_string()).unwrap(),
+                hold_time: None,
+                actions: vec![RuleAction::SetPttEnable(true)],
+            }],
+            ..Default::default()
+        };
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::InterlockStateChanged("high_swr".to_string(), true)
+        );
+        while rx.try_recv().is_ok() {}
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::InputStateChanged("carrier".to_string(), true)
+        );
+        wait_millis!(10);
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        assert!(events.contains(&Event::SendStatus(Some(
+            "Refusing to enable for rule \"carrier\", interlocks tripped: high_swr".to_string()
+        ))));
+        assert!(!events.contains(&Event::SetPttEnable(true)));
+
+        // Carried out once nothing prevents it
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::InterlockStateChanged("high_swr".to_string(), false)
+        );
+        while rx.try_recv().is_ok() {}
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::RuleEnable("carrier".to_string(), Output::PttEnable)
+        );
+        assert_eq!(Event::SetPttEnable(true), rx.try_recv().unwrap());
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rule_lockout_after_hold_time() {
+        let config = Config {
+            rules: vec![Rule {
+                name: "stuck_ptt".to_string(),
+                condition: Expression::try_from("ptt_active && !ptt_enabled".to_string()).unwrap(),
+                hold_time: Some(Duration::from_millis(500)),
+                actions: vec![RuleAction::Lockout("PTT stuck".to_string())],
+            }],
+            ..Default::default()
+        };
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(tx, rx, Event::PttEnableStateChanged(true));
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+
+        send_tx_on!(tx, rx);
+
+        // PTT is expected to be active while it is enabled.
+        wait_millis!(600);
+        expect_no_event!(rx);
+
+        send_event_receive_it_and_yield!(tx, rx, Event::PttEnableStateChanged(false));
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+
+        wait_millis!(450);
+        expect_no_event!(rx);
+
+        wait_millis!(100);
+        assert_eq!(
+            Event::Lockout("PTT stuck".to_string()),
+            rx.try_recv().unwrap()
+        );
+        assert_eq!(Event::SetTxPowerEnable(false), rx.try_recv().unwrap());
+        assert_eq!(Event::SetPttEnable(false), rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SendStatus(Some("Locked out: PTT stuck".to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new("", "{\"enable_ptt\":true}",))
+        );
+        assert_eq!(
+            Event::SendStatus(Some(
+                "Refusing to enable, locked out: PTT stuck".to_string()
+            )),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new("", "{\"reset_lockout\":true}",))
+        );
+        assert_eq!(Event::ResetLockout, rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SendStatus(Some("Lockout reset".to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stats_on_request() {
+        let mut config = Config::default();
+        config.mqtt.stats_topic = Some("stats".to_string());
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_tx_on!(tx, rx);
+        wait_millis!(200);
+        send_tx_off!(tx, rx);
+
+        send_tx_on!(tx, rx);
+        wait_millis!(100);
+        send_tx_off!(tx, rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new("", "{\"request_stats\":true}",))
+        );
+        assert_eq!(Event::SendStats, rx.try_recv().unwrap());
+
+        let stats: serde_json::Value = match rx.try_recv().unwrap() {
+            Event::MqttMessageSend(msg) => serde_json::from_str(&msg.message).unwrap(),
+            _ => panic!("Expected stats message"),
+        };
+        for period in ["since_startup", "today"] {
+            assert_eq!(2, stats[period]["key_ups"]);
+            assert_eq!(1, stats[period]["commands"]);
+            assert!(stats[period]["tx_time_ms"].as_u64().unwrap() >= 300);
+            assert!(stats[period]["longest_tx_ms"].as_u64().unwrap() >= 200);
+            assert!(stats[period]["average_tx_ms"].as_u64().unwrap() >= 150);
+        }
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn timed_enable_reverts() {
+        let config = Config::default();
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "",
+                "{\"enable_ptt\":true, \"duration\":500}",
+            ))
+        );
+        assert_eq!(Event::SetPttEnable(true), rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SetPttEnableTimeout(Some(Duration::from_millis(500))),
+            rx.try_recv().unwrap()
+        );
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+
+        wait_millis!(300);
+        expect_no_event!(rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new("", "{\"extend_duration\":500}",))
+        );
+        assert_eq!(
+            Event::ExtendEnableTimeouts(Duration::from_millis(500)),
+            rx.try_recv().unwrap()
+        );
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        let status: serde_json::Value = match rx.try_recv().unwrap() {
+            Event::MqttMessageSend(msg) => serde_json::from_str(&msg.message).unwrap(),
+            _ => panic!("Expected status message"),
+        };
+        let remaining = status["status"]["ptt_enable_remaining_ms"]
+            .as_u64()
+            .unwrap();
+        assert!(remaining > 600 && remaining <= 700);
+        assert!(status["status"]["tx_power_enable_remaining_ms"].is_null());
+
+        // Would have expired by now without the extension.
+        wait_millis!(400);
+        expect_no_event!(rx);
+
+        wait_millis!(350);
+        assert_eq!(Event::SetPttEnable(false), rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SendStatus(Some("Timed PTT enable expired".to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn timed_enable_cancel() {
+        let config = Config::default();
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "",
+                "{\"enable_tx_power\":true, \"duration\":500}",
+            ))
+        );
+        assert_eq!(Event::SetTxPowerEnable(true), rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SetTxPowerEnableTimeout(Some(Duration::from_millis(500))),
+            rx.try_recv().unwrap()
+        );
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new("", "{\"cancel_duration\":true}",))
+        );
+        assert_eq!(Event::SetTxPowerEnableTimeout(None), rx.try_recv().unwrap());
+        assert_eq!(Event::SetPttEnableTimeout(None), rx.try_recv().unwrap());
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+        expect_mqtt_message!(rx);
+
+        wait_millis!(600);
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn untimed_enable_cancels_timed_enable() {
+        let config = Config::default();
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "",
+                "{\"enable_ptt\":true, \"duration\":300}",
+            ))
+        );
+        assert_eq!(Event::SetPttEnable(true), rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SetPttEnableTimeout(Some(Duration::from_millis(300))),
+            rx.try_recv().unwrap()
+        );
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new("", "{\"enable_ptt\":true}",))
+        );
+        assert_eq!(Event::SetPttEnable(true), rx.try_recv().unwrap());
+
+        // The earlier timed enable no longer reverts
+        wait_millis!(400);
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    macro_rules! expect_tx_guard_warning {
+        ($rx: expr, $remaining: expr) => {
+            assert_eq!(
+                Event::TxGuardWarning(Duration::from_millis($remaining)),
+                $rx.try_recv().unwrap()
+            );
+            assert_eq!(
+                Event::SendStatus(Some(format!("TX will time out in {}ms", $remaining))),
+                $rx.try_recv().unwrap()
+            );
+            expect_mqtt_message!($rx);
+        };
+    }
+
+    #[tokio::test]
+    async fn tx_guard_warnings() {
+        let config = Config {
+            tx_guard_time: Some(Duration::from_millis(500)),
+            tx_guard_warnings: vec![Duration::from_millis(100), Duration::from_millis(300)],
+            ..Default::default()
+        };
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_tx_on!(tx, rx);
+
+        wait_millis!(150);
+        expect_no_event!(rx);
+
+        // 300ms remaining
+        wait_millis!(100);
+        expect_tx_guard_warning!(rx, 300);
+
+        wait_millis!(100);
+        expect_no_event!(rx);
+
+        // 100ms remaining
+        wait_millis!(100);
+        expect_tx_guard_warning!(rx, 100);
+
+        wait_millis!(100);
+        expect_tx_guard_closedown!(rx);
+
+        // PTT released after the first warning, no further warnings or closedown.
+        send_tx_on!(tx, rx);
+        wait_millis!(250);
+        expect_tx_guard_warning!(rx, 300);
+        send_tx_off!(tx, rx);
+
+        wait_millis!(500);
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn command_response_mqtt_v5() {
+        let config = Config::default();
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::InterlockStateChanged("high_swr".to_string(), true)
+        );
+        assert_eq!(Event::SetTxPowerEnable(false), rx.try_recv().unwrap());
+        assert_eq!(Event::SetPttEnable(false), rx.try_recv().unwrap());
+        assert!(matches!(rx.try_recv().unwrap(), Event::SendStatus(Some(_))));
+        expect_mqtt_message!(rx);
+
+        let mut request =
+            MqttMessageEvent::new("", "{\"enable_ptt\":true, \"request_stats\":true}");
+        request.response_topic = Some("reply".to_string());
+        request.correlation_data = Some(vec![1, 2, 3]);
+        let request = Event::MqttMessageReceive(request);
+        send_event_receive_it_and_yield!(tx, rx, request);
+        assert_eq!(
+            Event::SendStatus(Some(
+                "Refusing to enable, interlocks tripped: high_swr".to_string()
+            )),
+            rx.try_recv().unwrap()
+        );
+        assert_eq!(Event::SendStats, rx.try_recv().unwrap());
+
+        let response = match rx.try_recv().unwrap() {
+            Event::MqttMessageSend(msg) => msg,
+            e => panic!("Expected command response, got {:?}", e),
+        };
+        assert_eq!(
+            MqttMessageEvent::new("reply", &response.message)
+                .with_correlation_data(Some(vec![1, 2, 3])),
+            response
+        );
+        let response: serde_json::Value = serde_json::from_str(&response.message).unwrap();
+        assert!(response["request_id"].is_null());
+        assert_eq!("partially_applied", response["outcome"]);
+        assert_eq!(
+            serde_json::json!([
+                {
+                    "action": "ptt_enable",
+                    "outcome": "refused",
+                    "reason": "interlocks tripped: high_swr"
+                },
+                {
+                    "action": "request_stats",
+                    "outcome": "applied",
+                    "reason": null
+                }
+            ]),
+            response["results"]
+        );
+        expect_mqtt_message!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn command_response_request_id() {
+        let mut config = config_with_outputs();
+        config.mqtt.response_topic = Some("reply".to_string());
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "",
+                "{\"request_id\":\"abc\", \"enable_tx_power\":true}",
+            ))
+        );
+        assert_eq!(Event::SetTxPowerEnable(true), rx.try_recv().unwrap());
+
+        // Not acknowledged until the output has been set
+        expect_no_event!(rx);
+        send_event_receive_it_and_yield!(tx, rx, Event::TxPowerEnableStateChanged(true));
+
+        let response: serde_json::Value = match rx.try_recv().unwrap() {
+            Event::MqttMessageSend(msg) => serde_json::from_str(&msg.message).unwrap(),
+            e => panic!("Expected command response, got {:?}", e),
+        };
+        assert_eq!("abc", response["request_id"]);
+        assert_eq!("accepted", response["outcome"]);
+        assert_eq!("applied", response["results"][0]["outcome"]);
+
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn command_response_output_failure() {
+        let mut config = config_with_outputs();
+        config.mqtt.response_topic = Some("reply".to_string());
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "",
+                "{\"enable_ptt\":false, \"enable_tx_power\":false}",
+            ))
+        );
+        assert_eq!(Event::SetTxPowerEnable(false), rx.try_recv().unwrap());
+        assert_eq!(Event::SetPttEnable(false), rx.try_recv().unwrap());
+
+        send_event_receive_it_and_yield!(tx, rx, Event::PttEnableFailed("GPIO error".to_string()));
+        assert_eq!(
+            Event::SendStatus(Some("Failed to set PTT enable".to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        // TX power enable output never confirms
+        wait_millis!(1000);
+        assert_eq!(Event::CommandAckTimeout(0), rx.try_recv().unwrap());
+
+        let response: serde_json::Value = match rx.try_recv().unwrap() {
+            Event::MqttMessageSend(msg) => serde_json::from_str(&msg.message).unwrap(),
+            e => panic!("Expected command response, got {:?}", e),
+        };
+        assert_eq!("rejected", response["outcome"]);
+        assert_eq!(
+            serde_json::json!([
+                {
+                    "action": "tx_power_enable",
+                    "outcome": "unconfirmed",
+                    "reason": "No confirmation from output"
+                },
+                {
+                    "action": "ptt_enable",
+                    "outcome": "failed",
+                    "reason": "GPIO error"
+                }
+            ]),
+            response["results"]
+        );
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn command_response_parse_error() {
+        let config = Config::default();
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "",
+                "{\"request_id\":\"abc\", \"response_topic\":\"reply\", \"enable_ptt\":\"yes\"}",
+            ))
+        );
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Event::SendStatus(Some(msg)) if msg.starts_with("Rejected command: invalid type")
+        ));
+
+        let response = match rx.try_recv().unwrap() {
+            Event::MqttMessageSend(msg) => msg,
+            e => panic!("Expected command response, got {:?}", e),
+        };
+        assert_eq!(MqttMessageEvent::new("reply", &response.message), response);
+        let response: serde_json::Value = serde_json::from_str(&response.message).unwrap();
+        assert_eq!("abc", response["request_id"]);
+        assert_eq!("rejected", response["outcome"]);
+        assert!(response["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid type"));
+
+        expect_mqtt_message!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn command_response_nothing_to_do() {
+        let mut config = Config::default();
+        config.mqtt.response_topic = Some("reply".to_string());
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        // Unknown fields are ignored, and there is no PTT enable output to set
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "",
+                "{\"enable_ptt\":true, \"enable_pa\":true}",
+            ))
+        );
+        assert_eq!(Event::SetPttEnable(true), rx.try_recv().unwrap());
+
+        let response: serde_json::Value = match rx.try_recv().unwrap() {
+            Event::MqttMessageSend(msg) => serde_json::from_str(&msg.message).unwrap(),
+            e => panic!("Expected command response, got {:?}", e),
+        };
+        assert_eq!("nothing_to_do", response["outcome"]);
+        assert_eq!(
+            serde_json::json!([
+                {
+                    "action": "ptt_enable",
+                    "outcome": "ignored",
+                    "reason": "no output configured"
+                }
+            ]),
+            response["results"]
+        );
+        expect_no_event!(rx);
+
+        // Not locked out
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new("", "{\"reset_lockout\":true}"))
+        );
+        let response: serde_json::Value = match rx.try_recv().unwrap() {
+            Event::MqttMessageSend(msg) => serde_json::from_str(&msg.message).unwrap(),
+            e => panic!("Expected command response, got {:?}", e),
+        };
+        assert_eq!("nothing_to_do", response["outcome"]);
+        assert_eq!("not locked out", response["results"][0]["reason"]);
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unsigned_command_rejected() {
+        let config = Config {
+            command_signing: Some(CommandSigning {
+                required: true,
+                replay_window: Some(Duration::from_secs(30)),
+            }),
+            ..Default::default()
+        };
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new("", "{\"enable_ptt\":true}"))
+        );
+        assert_eq!(
+            Event::SendStatus(Some("Rejected command: command is not signed".to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn operator_permission_denied() {
+        let mut config = Config {
+            operators: vec![Operator {
+                name: "alice".to_string(),
+                permissions: Some(vec![Permission::Closedown]),
+                ..Default::default()
+            }],
+            ..config_with_outputs()
+        };
+        config.mqtt.command_topic = "command".to_string();
+        config.mqtt.operator_subtopics = true;
+        config.mqtt.trust_operator_claims = true;
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "command/alice",
+                "{\"response_topic\":\"reply\", \"enable_ptt\":true, \"enable_tx_power\":false}",
+            ))
+        );
+        assert_eq!(Event::SetTxPowerEnable(false), rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SendStatus(Some(
+                "Denied ptt_enable, operator \"alice\" does not have permission".to_string()
+            )),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        send_event_receive_it_and_yield!(tx, rx, Event::TxPowerEnableStateChanged(false));
+        let response = match rx.try_recv().unwrap() {
+            Event::MqttMessageSend(msg) => msg,
+            e => panic!("Expected command response, got {:?}", e),
+        };
+        let response: serde_json::Value = serde_json::from_str(&response.message).unwrap();
+        assert_eq!("partially_applied", response["outcome"]);
+        assert_eq!("denied", response["results"][1]["outcome"]);
+        assert_eq!("applied", response["results"][0]["outcome"]);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_operator_commands_unsigned() {
+        let config = Config {
+            operators: vec![Operator {
+                name: "alice".to_string(),
+                ..Default::default()
+            }],
+            command_signing: Some(CommandSigning {
+                required: true,
+                replay_window: Some(Duration::from_secs(30)),
+            }),
+            ..config_with_outputs()
+        };
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        // Authenticated by the local API as an operator
+        let request = LocalCommandEvent::new(
+            "test",
+            Some("alice".to_string()),
+            LocalRequest::Command("{\"enable_ptt\":false}".to_string()),
+        );
+        let request = Event::LocalCommand(request);
+        send_event_receive_it_and_yield!(tx, rx, request);
+        assert_eq!(Event::SetPttEnable(false), rx.try_recv().unwrap());
+        expect_no_event!(rx);
+
+        // Anonymous
+        let request = LocalCommandEvent::new(
+            "test",
+            None,
+            LocalRequest::Command("{\"enable_ptt\":false}".to_string()),
+        );
+        let id = request.id;
+        let request = Event::LocalCommand(request);
+        send_event_receive_it_and_yield!(tx, rx, request);
+        assert_eq!(
+            Event::SendStatus(Some("Rejected command: command is not signed".to_string())),
+            rx.try_recv().unwrap()
+        );
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Event::LocalCommandResponse(reply_id, response)
+                if reply_id == id && response.error == Some("command is not signed".to_string())
+        ));
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_commands() {
+        let config = Config {
+            operators: vec![Operator {
+                name: "alice".to_string(),
+                permissions: Some(vec![Permission::Closedown]),
+                ..Default::default()
+            }],
+            ..config_with_outputs()
+        };
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        let request =
+            LocalCommandEvent::new("test", Some("eve".to_string()), LocalRequest::Closedown);
+        let id = request.id;
+        let request = Event::LocalCommand(request);
+        send_event_receive_it_and_yield!(tx, rx, request);
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Event::LocalCommandResponse(reply_id, response)
+                if reply_id == id && response.error == Some("unknown operator \"eve\"".to_string())
+        ));
+        expect_no_event!(rx);
+
+        let request = LocalCommandEvent::new(
+            "test",
+            Some("alice".to_string()),
+            LocalRequest::Command("{\"enable_ptt\":true}".to_string()),
+        );
+        let id = request.id;
+        let request = Event::LocalCommand(request);
+        send_event_receive_it_and_yield!(tx, rx, request);
+        assert_eq!(
+            Event::SendStatus(Some(
+                "Denied ptt_enable, operator \"alice\" does not have permission".to_string()
+            )),
+            rx.try_recv().unwrap()
+        );
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Event::LocalCommandResponse(reply_id, response)
+                if reply_id == id && response.results[0].outcome == ActionOutcome::Denied
+        ));
+        expect_mqtt_message!(rx);
+
+        let request =
+            LocalCommandEvent::new("test", Some("alice".to_string()), LocalRequest::Closedown);
+        let id = request.id;
+        let request = Event::LocalCommand(request);
+        send_event_receive_it_and_yield!(tx, rx, request);
+        assert_eq!(Event::SetTxPowerEnable(false), rx.try_recv().unwrap());
+        assert_eq!(Event::SetPttEnable(false), rx.try_recv().unwrap());
+        expect_no_event!(rx);
+
+        send_event_receive_it_and_yield!(tx, rx, Event::TxPowerEnableStateChanged(false));
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+
+        send_event_receive_it_and_yield!(tx, rx, Event::PttEnableStateChanged(false));
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Event::LocalCommandResponse(reply_id, response)
+                if reply_id == id && response.outcome == CommandOutcome::Accepted
+        ));
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        expect_mqtt_message!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    macro_rules! expect_sequence_fields {
+        ($rx: expr, $sequence: expr) => {
+            assert_eq!(
+                Event::MqttMessageSend(MqttMessageEvent::new_retained(
+                    "status/sequence",
+                    $sequence
+                )),
+                $rx.try_recv().unwrap()
+            );
+            assert!(matches!(
+                $rx.try_recv().unwrap(),
+                Event::MqttMessageSend(msg) if msg.topic() == "status/uptime_ms"
+            ));
+        };
+    }
+
+    #[tokio::test]
+    async fn status_fields() {
+        let mut config = Config::default();
+        config.mqtt.status_topic = "status".to_string();
+        config.mqtt.status_format = StatusFormat::Fields;
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(tx, rx, Event::PttStateChanged(true));
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+
+        // All fields are published the first time
+        for (field, payload) in [
+            ("interlocks_tripped", ""),
+            ("lockout", ""),
+            ("mqtt_broker", ""),
+            ("ptt_active", "true"),
+            ("ptt_enable_remaining_ms", ""),
+            ("ptt_enabled", ""),
+            ("tx_guard_remaining_ms", ""),
+            ("tx_power_active", ""),
+            ("tx_power_enable_remaining_ms", ""),
+            ("tx_power_enabled", ""),
+        ] {
+            assert_eq!(
+                Event::MqttMessageSend(MqttMessageEvent::new_retained(
+                    &format!("status/{}", field),
+                    payload
+                )),
+                rx.try_recv().unwrap()
+            );
+        }
+        expect_sequence_fields!(rx, "1");
+        expect_no_event!(rx);
+
+        // Only changed fields are published after that
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::InterlockStateChanged("door_open".to_string(), true)
+        );
+        assert_eq!(Event::SetTxPowerEnable(false), rx.try_recv().unwrap());
+        assert_eq!(Event::SetPttEnable(false), rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SendStatus(Some("Interlock \"door_open\" tripped".to_string())),
+            rx.try_recv().unwrap()
+        );
+        assert_eq!(
+            Event::MqttMessageSend(MqttMessageEvent::new_retained(
+                "status/interlocks_tripped",
+                "door_open"
+            )),
+            rx.try_recv().unwrap()
+        );
+        expect_sequence_fields!(rx, "2");
+        assert_eq!(
+            Event::MqttMessageSend(MqttMessageEvent::new(
+                "status/message",
+                "Interlock \"door_open\" tripped"
+            )),
+            rx.try_recv().unwrap()
+        );
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn homie_set_refused_while_locked_out() {
+        let mut config = Config::default();
+        config.mqtt.homie = Some(Homie {
+            base_topic: "homie".to_string(),
+            device_id: "station".to_string(),
+            name: "Station".to_string(),
+        });
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(tx, rx, Event::PttEnableStateChanged(false));
+        assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+        assert_eq!(
+            Event::MqttMessageSend(MqttMessageEvent::new_retained(
+                "homie/station/ptt/enable",
+                "false"
+            )),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "homie/station/ptt/enable/set",
+                "true"
+            ))
+        );
+        assert_eq!(Event::SetPttEnable(true), rx.try_recv().unwrap());
+
+        send_event_receive_it_and_yield!(tx, rx, Event::Lockout("test".to_string()));
+        assert_eq!(Event::SetTxPowerEnable(false), rx.try_recv().unwrap());
+        assert_eq!(Event::SetPttEnable(false), rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SendStatus(Some("Locked out: test".to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "homie/station/ptt/enable/set",
+                "true"
+            ))
+        );
+        assert_eq!(
+            Event::SendStatus(Some("Refusing to enable, locked out: test".to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn homie_set_refused_when_signing_required() {
+        let mut config = Config {
+            command_signing: Some(CommandSigning {
+                required: true,
+                replay_window: Some(Duration::from_secs(30)),
+            }),
+            ..Default::default()
+        };
+        config.mqtt.homie = Some(Homie {
+            base_topic: "homie".to_string(),
+            device_id: "station".to_string(),
+            name: "Station".to_string(),
+        });
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new(
+                "homie/station/ptt/enable/set",
+                "true"
+            ))
+        );
+        assert_eq!(
+            Event::SendStatus(Some(
+                "Rejected command: Homie set messages can't be signed".to_string()
+            )),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn commands_handled_after_lag() {
+        let config = Config::default();
+        let (tx, _) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+        tokio::task::yield_now().await;
+
+        // Overflow the bus before processing gets to run, the command is still queued
+        for _ in 0..20 {
+            tx.send(Event::TxPowerStateChanged(false)).unwrap();
+        }
+        tx.send(Event::MqttMessageReceive(MqttMessageEvent::new(
+            "",
+            "{\"enable_ptt\":false}",
+        )))
+        .unwrap();
+
+        let mut rx = tx.subscribe();
+        tokio::task::yield_now().await;
+        assert_eq!(Event::Resync, rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SendStatus(Some("Missed 5 events, resynchronising".to_string())),
+            rx.try_recv().unwrap()
+        );
+        assert_eq!(Event::SetPttEnable(false), rx.try_recv().unwrap());
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn safety_events_kept_after_lag() {
+        let mut config = Config::default();
+        config.tx_guard_time = Some(Duration::from_millis(500));
+        let (tx, _) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+        tokio::task::yield_now().await;
+
+        // Overflow the bus before processing gets to run, with a lockout and TX guard trip queued
+        for _ in 0..20 {
+            tx.send(Event::TxPowerStateChanged(false)).unwrap();
+        }
+        tx.send(Event::Lockout("test".to_string())).unwrap();
+        tx.send(Event::TxGuardTripped).unwrap();
+
+        let mut rx = tx.subscribe();
+        tokio::task::yield_now().await;
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        assert!(events.contains(&Event::SendStatus(Some("Locked out: test".to_string()))));
+        assert!(events.contains(&Event::SendStatus(Some(
+            "TX timed out after 500ms".to_string()
+        ))));
+
+        // Still locked out
+        send_event_receive_it_and_yield!(
+            tx,
+            rx,
+            Event::MqttMessageReceive(MqttMessageEvent::new("", "{\"enable_ptt\":true}"))
+        );
+        assert_eq!(
+            Event::SendStatus(Some("Refusing to enable, locked out: test".to_string())),
+            rx.try_recv().unwrap()
+        );
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn status_heartbeat() {
+        let config = Config {
+            status_interval: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let (tx, mut rx) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        wait_millis!(50);
+        for sequence in 1..=2 {
+            wait_millis!(100);
+            assert_eq!(Event::SendStatus(None), rx.try_recv().unwrap());
+            let response = match rx.try_recv().unwrap() {
+                Event::MqttMessageSend(msg) => msg,
+                e => panic!("Expected status, got {:?}", e),
+            };
+            let response: serde_json::Value = serde_json::from_str(&response.message).unwrap();
+            assert_eq!(sequence, response["sequence"]);
+            assert!(response["uptime_ms"].as_u64().unwrap() >= sequence * 100);
+            expect_no_event!(rx);
+        }
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn missed_events_resync() {
+        let config = Config::default();
+        let (tx, _) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), config).unwrap();
+
+        // Flood the bus with events that need no response, without giving the task a chance to run
+        for id in 0..20 {
+            tx.send(Event::CommandAckTimeout(id)).unwrap();
+        }
+        let mut rx = tx.subscribe();
+        wait_millis!(10);
+
+        assert_eq!(Event::Resync, rx.try_recv().unwrap());
+        assert_eq!(
+            Event::SendStatus(Some("Missed 4 events, resynchronising".to_string())),
+            rx.try_recv().unwrap()
+        );
+        expect_mqtt_message!(rx);
+        expect_no_event!(rx);
+
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+    }
 }
@@ -0,0 +1,345 @@
+use crate::{
+    config::{Config, Rule, RuleAction},
+    event::Event,
+    schema::{Output, Status},
+};
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use tokio::{sync::broadcast::Sender, task::JoinHandle};
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Channel {
+    TxPowerEnabled,
+    TxPowerActive,
+    PttEnabled,
+    PttActive,
+    Lockout,
+    Interlock(String),
+    Input(String),
+}
+
+impl Channel {
+    fn from_identifier(ident: &str) -> Result<Self> {
+        Ok(match ident {
+            "tx_power_enabled" => Self::TxPowerEnabled,
+            "tx_power_active" => Self::TxPowerActive,
+            "ptt_enabled" => Self::PttEnabled,
+            "ptt_active" => Self::PttActive,
+            "lockout" => Self::Lockout,
+            _ => match ident.split_once('.') {
+                Some(("interlock", name)) if !name.is_empty() => Self::Interlock(name.to_string()),
+                Some(("input", name)) if !name.is_empty() => Self::Input(name.to_string()),
+                _ => bail!("Unknown channel \"{}\"", ident),
+            },
+        })
+    }
+
+    fn state(&self, status: &Status) -> bool {
+        match self {
+            Self::TxPowerEnabled => status.tx_power_enabled == Some(true),
+            Self::TxPowerActive => status.tx_power_active == Some(true),
+            Self::PttEnabled => status.ptt_enabled == Some(true),
+            Self::PttActive => status.ptt_active == Some(true),
+            Self::Lockout => status.lockout.is_some(),
+            Self::Interlock(name) => status.interlocks_tripped.contains(name),
+            Self::Input(name) => status.inputs.get(name) == Some(&true),
+        }
+    }
+}
+
+/// A boolean expression over channel states, e.g. `interlock.mains_fail && !ptt_active`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) enum Expression {
+    Constant(bool),
+    Channel(Channel),
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    pub(crate) fn evaluate(&self, status: &Status) -> bool {
+        match self {
+            Self::Constant(v) => *v,
+            Self::Channel(c) => c.state(status),
+            Self::Not(e) => !e.evaluate(status),
+            Self::And(a, b) => a.evaluate(status) && b.evaluate(status),
+            Self::Or(a, b) => a.evaluate(status) || b.evaluate(status),
+        }
+    }
+
+    pub(crate) fn channels(&self) -> Vec<&Channel> {
+        match self {
+            Self::Constant(_) => Vec::new(),
+            Self::Channel(c) => vec![c],
+            Self::Not(e) => e.channels(),
+            Self::And(a, b) | Self::Or(a, b) => {
+                let mut v = a.channels();
+                v.extend(b.channels());
+                v
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        let tokens = tokenize(&s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            Some(t) => Err(anyhow!("Unexpected \"{}\" in expression \"{}\"", t, s)),
+            None => Ok(expr),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' | ')' | '!' => tokens.push(c.to_string()),
+            '&' | '|' => match chars.next() {
+                Some(n) if n == c => tokens.push(format!("{}{}", c, n)),
+                _ => bail!("Expected \"{}{}\" in expression \"{}\"", c, c, s),
+            },
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut ident = c.to_string();
+                while let Some(&n) = chars.peek() {
+                    if n.is_ascii_alphanumeric() || n == '_' || n == '.' {
+                        ident.push(n);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(ident);
+            }
+            _ => bail!("Unexpected character '{}' in expression \"{}\"", c, s),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<String> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn accept(&mut self, token: &str) -> bool {
+        if self.tokens.get(self.pos).map(|t| t.as_str()) == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Expression> {
+        let mut lhs = self.and()?;
+        while self.accept("||") {
+            lhs = Expression::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expression> {
+        let mut lhs = self.unary()?;
+        while self.accept("&&") {
+            lhs = Expression::And(Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expression> {
+        if self.accept("!") {
+            return Ok(Expression::Not(Box::new(self.unary()?)));
+        }
+
+        match self.next().as_deref() {
+            Some("(") => {
+                let expr = self.or()?;
+                match self.accept(")") {
+                    true => Ok(expr),
+                    false => Err(anyhow!("Missing closing parenthesis")),
+                }
+            }
+            Some("true") => Ok(Expression::Constant(true)),
+            Some("false") => Ok(Expression::Constant(false)),
+            Some(t @ (")" | "&&" | "||")) => Err(anyhow!("Unexpected \"{}\"", t)),
+            Some(ident) => Ok(Expression::Channel(Channel::from_identifier(ident)?)),
+            None => Err(anyhow!("Unexpected end of expression")),
+        }
+    }
+}
+
+/// Checks that a rule only refers to channels that exist and actually does something.
+pub(crate) fn validate(rule: &Rule, config: &Config) -> Result<()> {
+    for channel in rule.condition.channels() {
+        match channel {
+            Channel::Interlock(name) if !config.interlocks.iter().any(|i| &i.name == name) => {
+                bail!(
+                    "Rule \"{}\" refers to unknown interlock \"{}\"",
+                    rule.name,
+                    name
+                );
+            }
+            Channel::Input(name) if !config.inputs.iter().any(|i| &i.name == name) => {
+                bail!(
+                    "Rule \"{}\" refers to unknown input \"{}\"",
+                    rule.name,
+                    name
+                );
+            }
+            _ => {}
+        }
+    }
+
+    if rule.actions.is_empty() {
+        bail!("Rule \"{}\" has no actions", rule.name);
+    }
+
+    Ok(())
+}
+
+struct RuleState {
+    rule: Rule,
+    active: bool,
+    task: Option<JoinHandle<()>>,
+}
+
+/// Tracks the state of each configured rule and triggers its actions when its condition becomes true.
+pub(crate) struct Rules {
+    rules: Vec<RuleState>,
+}
+
+impl Rules {
+    pub(crate) fn new(rules: &[Rule]) -> Self {
+        Self {
+            rules: rules
+                .iter()
+                .map(|rule| RuleState {
+                    rule: rule.clone(),
+                    active: false,
+                    task: None,
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn evaluate(&mut self, tx: &Sender<Event>, status: &Status) {
+        for state in self.rules.iter_mut() {
+            let active = state.rule.condition.evaluate(status);
+
+            if active && !state.active {
+                log::info!("Rule \"{}\" condition met", state.rule.name);
+
+                let tx = tx.clone();
+                let rule = state.rule.clone();
+                state.task = Some(tokio::spawn(async move {
+                    if let Some(hold_time) = rule.hold_time {
+                        tokio::time::sleep(hold_time).await;
+                    }
+                    log::info!("Rule \"{}\" triggered", rule.name);
+                    for action in &rule.actions {
+                        crate::send_event!(tx, action.to_event(&rule.name));
+                    }
+                }));
+            } else if !active && state.active {
+                log::info!("Rule \"{}\" condition no longer met", state.rule.name);
+
+                if let Some(task) = state.task.take() {
+                    task.abort();
+                }
+            }
+
+            state.active = active;
+        }
+    }
+}
+
+impl RuleAction {
+    /// Event for the action of a rule, enables are checked by processing before being carried out.
+    fn to_event(&self, rule: &str) -> Event {
+        match self {
+            Self::SetTxPowerEnable(true) => {
+                Event::RuleEnable(rule.to_string(), Output::TxPowerEnable)
+            }
+            Self::SetTxPowerEnable(false) => Event::SetTxPowerEnable(false),
+            Self::SetPttEnable(true) => Event::RuleEnable(rule.to_string(), Output::PttEnable),
+            Self::SetPttEnable(false) => Event::SetPttEnable(false),
+            Self::SendMessage(msg) => Event::SendStatus(Some(msg.clone())),
+            Self::Lockout(reason) => Event::Lockout(reason.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Expression> {
+        Expression::try_from(s.to_string())
+    }
+
+    #[test]
+    fn parse_precedence() {
+        assert_eq!(
+            parse("ptt_active || !lockout && interlock.door_open").unwrap(),
+            Expression::Or(
+                Box::new(Expression::Channel(Channel::PttActive)),
+                Box::new(Expression::And(
+                    Box::new(Expression::Not(Box::new(Expression::Channel(
+                        Channel::Lockout
+                    )))),
+                    Box::new(Expression::Channel(Channel::Interlock(
+                        "door_open".to_string()
+                    ))),
+                )),
+            )
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse("").is_err());
+        assert!(parse("ptt_active &&").is_err());
+        assert!(parse("ptt_active & lockout").is_err());
+        assert!(parse("(ptt_active").is_err());
+        assert!(parse("ptt_active)").is_err());
+        assert!(parse("squelch_open").is_err());
+        assert!(parse("interlock.").is_err());
+        assert!(parse("output.pa").is_err());
+    }
+
+    #[test]
+    fn evaluate() {
+        let expr = parse("interlock.mains_fail && (input.battery_low || !ptt_enabled)").unwrap();
+
+        let mut status = Status::default();
+        assert!(!expr.evaluate(&status));
+
+        status.interlocks_tripped.insert("mains_fail".to_string());
+        assert!(expr.evaluate(&status));
+
+        status.ptt_enabled = Some(true);
+        assert!(!expr.evaluate(&status));
+
+        status.inputs.insert("battery_low".to_string(), true);
+        assert!(expr.evaluate(&status));
+    }
+}
@@ -1,19 +1,72 @@
-use crate::event::Event;
+use crate::{event::Event, stats::Stats};
 use chrono::{offset::Local, DateTime};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::time::Duration;
 
-#[derive(Clone, Default, Debug, Serialize)]
+#[derive(Clone, Default, Debug, PartialEq, Serialize)]
 pub(crate) struct Status {
     pub tx_power_enabled: Option<bool>,
     pub tx_power_active: Option<bool>,
     pub ptt_enabled: Option<bool>,
     pub ptt_active: Option<bool>,
+    pub tx_power_enable_remaining_ms: Option<u64>,
+    pub ptt_enable_remaining_ms: Option<u64>,
+    /// Time until the TX guard trips, while transmitting
+    pub tx_guard_remaining_ms: Option<u64>,
+    pub interlocks_tripped: BTreeSet<String>,
+    pub inputs: BTreeMap<String, bool>,
+    pub lockout: Option<String>,
+    pub mqtt_broker: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+impl Status {
+    /// Flattens the status into (subtopic, payload) pairs with simple payloads.
+    pub(crate) fn fields(&self) -> Vec<(String, String)> {
+        fn flatten(prefix: &str, value: &Value, fields: &mut Vec<(String, String)>) {
+            match value {
+                Value::Object(map) => {
+                    for (k, v) in map {
+                        let name = match prefix {
+                            "" => k.clone(),
+                            _ => format!("{}/{}", prefix, k),
+                        };
+                        flatten(&name, v, fields);
+                    }
+                }
+                Value::Null => fields.push((prefix.to_string(), String::new())),
+                Value::String(s) => fields.push((prefix.to_string(), s.clone())),
+                Value::Array(a) => fields.push((
+                    prefix.to_string(),
+                    a.iter()
+                        .map(|v| match v {
+                            Value::String(s) => s.clone(),
+                            v => v.to_string(),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(","),
+                )),
+                v => fields.push((prefix.to_string(), v.to_string())),
+            }
+        }
+
+        let mut fields = Vec::new();
+        match serde_json::to_value(self) {
+            Ok(value) => flatten("", &value, &mut fields),
+            Err(e) => log::error!("Failed to serialize status: {}", e),
+        }
+        fields
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub(crate) struct Response {
     pub status: Status,
     pub message: Option<String>,
+    /// Incremented for every status published, so that missed updates can be detected
+    pub sequence: Option<u64>,
+    pub uptime_ms: Option<u64>,
     pub timestamp: DateTime<Local>,
 }
 
@@ -22,27 +75,261 @@ impl Response {
         Self {
             status,
             message,
+            sequence: None,
+            uptime_ms: None,
+            timestamp: Local::now(),
+        }
+    }
+
+    pub(crate) fn with_sequence(self, sequence: u64, uptime: Duration) -> Self {
+        Self {
+            sequence: Some(sequence),
+            uptime_ms: Some(uptime.as_millis() as u64),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct StatsResponse {
+    #[serde(flatten)]
+    pub stats: Stats,
+    pub timestamp: DateTime<Local>,
+}
+
+impl StatsResponse {
+    pub(crate) fn new(stats: Stats) -> Self {
+        Self {
+            stats,
+            timestamp: Local::now(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ActionOutcome {
+    /// Action has been carried out
+    Applied,
+    /// Action was not permitted in the current state
+    Refused,
+    /// Operator does not have permission for the action
+    Denied,
+    /// Action was attempted but failed
+    Failed,
+    /// Action has been requested but not yet confirmed
+    Pending,
+    /// Action was requested but never confirmed
+    Unconfirmed,
+    /// Action has nothing to act on, e.g. no output is configured for it
+    Ignored,
+}
+
+/// Output that reports when it has been set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Output {
+    TxPowerEnable,
+    PttEnable,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct ActionResult {
+    pub action: &'static str,
+    pub outcome: ActionOutcome,
+    pub reason: Option<String>,
+
+    /// Output (and the state it is being set to) that is yet to confirm the action has been applied
+    #[serde(skip)]
+    pub awaiting: Option<(Output, bool)>,
+}
+
+impl ActionResult {
+    pub(crate) fn action_name(event: &Event) -> &'static str {
+        match event {
+            Event::SetTxPowerEnable(_) => "tx_power_enable",
+            Event::SetPttEnable(_) => "ptt_enable",
+            Event::SetTxPowerEnableTimeout(_) => "tx_power_enable_duration",
+            Event::SetPttEnableTimeout(_) => "ptt_enable_duration",
+            Event::ExtendEnableTimeouts(_) => "extend_duration",
+            Event::ResetLockout => "reset_lockout",
+            Event::SendStats => "request_stats",
+            _ => "unknown",
+        }
+    }
+
+    /// Result for an action that has been sent for processing.
+    pub(crate) fn dispatched(event: &Event) -> Self {
+        let awaiting = match event {
+            Event::SetTxPowerEnable(state) => Some((Output::TxPowerEnable, *state)),
+            Event::SetPttEnable(state) => Some((Output::PttEnable, *state)),
+            _ => None,
+        };
+
+        Self {
+            action: Self::action_name(event),
+            outcome: match awaiting {
+                Some(_) => ActionOutcome::Pending,
+                None => ActionOutcome::Applied,
+            },
+            reason: None,
+            awaiting,
+        }
+    }
+
+    pub(crate) fn refused(event: &Event, reason: String) -> Self {
+        Self {
+            action: Self::action_name(event),
+            outcome: ActionOutcome::Refused,
+            reason: Some(reason),
+            awaiting: None,
+        }
+    }
+
+    pub(crate) fn denied(event: &Event, reason: String) -> Self {
+        Self {
+            outcome: ActionOutcome::Denied,
+            ..Self::refused(event, reason)
+        }
+    }
+
+    pub(crate) fn ignored(event: &Event, reason: String) -> Self {
+        Self {
+            outcome: ActionOutcome::Ignored,
+            ..Self::refused(event, reason)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CommandOutcome {
+    Accepted,
+    Rejected,
+    PartiallyApplied,
+    /// Command had no actions, or none that had anything to act on
+    NothingToDo,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct CommandResponse {
+    pub request_id: Option<String>,
+    pub outcome: CommandOutcome,
+    pub error: Option<String>,
+    pub results: Vec<ActionResult>,
+    pub timestamp: DateTime<Local>,
+}
+
+impl CommandResponse {
+    pub(crate) fn new(request_id: Option<String>, results: Vec<ActionResult>) -> Self {
+        let count = |outcome| results.iter().filter(|r| r.outcome == outcome).count();
+        let applied = count(ActionOutcome::Applied);
+        let actionable = results.len() - count(ActionOutcome::Ignored);
+
+        Self {
+            request_id,
+            outcome: if actionable == 0 {
+                CommandOutcome::NothingToDo
+            } else if applied == actionable {
+                CommandOutcome::Accepted
+            } else if applied == 0 {
+                CommandOutcome::Rejected
+            } else {
+                CommandOutcome::PartiallyApplied
+            },
+            error: None,
+            results,
             timestamp: Local::now(),
         }
     }
+
+    pub(crate) fn rejected(request_id: Option<String>, error: String) -> Self {
+        Self {
+            request_id,
+            outcome: CommandOutcome::Rejected,
+            error: Some(error),
+            results: Vec::new(),
+            timestamp: Local::now(),
+        }
+    }
+}
+
+/// A request to the control socket, sent as a single line of JSON.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub(crate) enum ControlRequest {
+    Status,
+    /// A command, as would be sent to the command topic
+    Command {
+        command: Value,
+    },
+    Closedown,
+}
+
+/// A command payload along with its signature.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SignedCommand {
+    pub operator: String,
+    /// Hex encoded signature of `payload`
+    pub signature: String,
+    /// JSON encoded `Command`
+    pub payload: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct Command {
+    pub request_id: Option<String>,
+    pub response_topic: Option<String>,
+
+    /// Unix time in milliseconds at which a signed command was created
+    pub timestamp: Option<i64>,
+    /// Unique value for each signed command, to detect replays
+    pub nonce: Option<String>,
+
     enable_tx_power: Option<bool>,
     enable_ptt: Option<bool>,
+    duration: Option<u64>,
+    extend_duration: Option<u64>,
+    cancel_duration: Option<bool>,
+    reset_lockout: Option<bool>,
+    request_stats: Option<bool>,
 }
 
 impl Command {
     pub(crate) fn generate_events(&self) -> Vec<Event> {
         let mut v = Vec::new();
 
+        let duration = self.duration.map(Duration::from_millis);
+
         if let Some(en) = self.enable_tx_power {
             v.push(Event::SetTxPowerEnable(en));
+            if en && duration.is_some() {
+                v.push(Event::SetTxPowerEnableTimeout(duration));
+            }
         }
 
         if let Some(en) = self.enable_ptt {
             v.push(Event::SetPttEnable(en));
+            if en && duration.is_some() {
+                v.push(Event::SetPttEnableTimeout(duration));
+            }
+        }
+
+        if let Some(ms) = self.extend_duration {
+            v.push(Event::ExtendEnableTimeouts(Duration::from_millis(ms)));
+        }
+
+        if let Some(true) = self.cancel_duration {
+            v.push(Event::SetTxPowerEnableTimeout(None));
+            v.push(Event::SetPttEnableTimeout(None));
+        }
+
+        if let Some(true) = self.reset_lockout {
+            v.push(Event::ResetLockout);
+        }
+
+        if let Some(true) = self.request_stats {
+            v.push(Event::SendStats);
         }
 
         v
@@ -0,0 +1,129 @@
+use crate::metrics::METRICS;
+use chrono::{offset::Local, DateTime, NaiveDate};
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize)]
+pub(crate) struct Statistics {
+    pub key_ups: u64,
+    pub tx_time_ms: u64,
+    pub longest_tx_ms: u64,
+    pub average_tx_ms: u64,
+    pub tx_guard_trips: u64,
+    pub commands: u64,
+    pub invalid_commands: u64,
+}
+
+impl Statistics {
+    fn record_tx(&mut self, duration: Duration) {
+        let ms = duration.as_millis() as u64;
+        self.tx_time_ms += ms;
+        self.longest_tx_ms = self.longest_tx_ms.max(ms);
+        self.average_tx_ms = self.tx_time_ms / self.key_ups.max(1);
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Stats {
+    pub startup: DateTime<Local>,
+    pub since_startup: Statistics,
+
+    pub day: NaiveDate,
+    pub today: Statistics,
+
+    /// Start of the current transmission and the day it started on
+    #[serde(skip)]
+    tx_start: Option<(Instant, NaiveDate)>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        let now = Local::now();
+        Self {
+            startup: now,
+            since_startup: Statistics::default(),
+            day: now.date_naive(),
+            today: Statistics::default(),
+            tx_start: None,
+        }
+    }
+}
+
+impl Stats {
+    /// Starts a new set of daily statistics if the day has changed since they were last updated.
+    pub(crate) fn roll_day(&mut self) {
+        let today = Local::now().date_naive();
+        if today != self.day {
+            self.day = today;
+            self.today = Statistics::default();
+        }
+    }
+
+    fn update(&mut self, f: impl Fn(&mut Statistics)) {
+        self.roll_day();
+        f(&mut self.since_startup);
+        f(&mut self.today);
+    }
+
+    pub(crate) fn ptt_state_changed(&mut self, active: bool) {
+        match (active, self.tx_start) {
+            (true, None) => {
+                self.update(|s| s.key_ups += 1);
+                self.tx_start = Some((Instant::now(), self.day));
+                METRICS.key_ups.inc();
+            }
+            (false, Some((start, day))) => {
+                self.tx_start = None;
+                let duration = start.elapsed();
+                self.roll_day();
+                self.since_startup.record_tx(duration);
+                // A transmission spanning midnight belongs to the day it was keyed up on
+                if day == self.day {
+                    self.today.record_tx(duration);
+                }
+                METRICS.transmission_length.observe(duration.as_secs_f64());
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn tx_guard_tripped(&mut self) {
+        self.update(|s| s.tx_guard_trips += 1);
+        METRICS.tx_guard_trips.inc();
+    }
+
+    pub(crate) fn command_received(&mut self, valid: bool) {
+        self.update(|s| match valid {
+            true => s.commands += 1,
+            false => s.invalid_commands += 1,
+        });
+        METRICS.commands.inc();
+        if !valid {
+            METRICS.rejected_commands.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transmission_over_midnight() {
+        let mut stats = Stats::default();
+        stats.ptt_state_changed(true);
+
+        // Keyed up yesterday
+        let yesterday = stats.day.pred_opt().unwrap();
+        stats.day = yesterday;
+        stats.tx_start = stats.tx_start.map(|(start, _)| (start, yesterday));
+
+        stats.ptt_state_changed(false);
+        assert_eq!(Statistics::default(), stats.today);
+        assert_eq!(1, stats.since_startup.key_ups);
+        assert_eq!(
+            stats.since_startup.tx_time_ms,
+            stats.since_startup.longest_tx_ms
+        );
+    }
+}
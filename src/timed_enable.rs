@@ -0,0 +1,64 @@
+use crate::event::Event;
+use tokio::{
+    sync::broadcast::Sender,
+    task::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Reverts an enable (i.e. sends a set enable false event) once a deadline has passed.
+pub(crate) struct TimedEnable {
+    name: &'static str,
+    revert_event: Event,
+    deadline: Option<Instant>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl TimedEnable {
+    pub(crate) fn new(name: &'static str, revert_event: Event) -> Self {
+        Self {
+            name,
+            revert_event,
+            deadline: None,
+            task: None,
+        }
+    }
+
+    /// Time left until the enable is reverted, `None` if it is not timed.
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    pub(crate) fn start(&mut self, tx: &Sender<Event>, duration: Duration) {
+        self.cancel();
+
+        log::info!("{} enabled for {}ms", self.name, duration.as_millis());
+        self.deadline = Some(Instant::now() + duration);
+
+        let tx = tx.clone();
+        let name = self.name;
+        let revert_event = self.revert_event.clone();
+        self.task = Some(tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            crate::send_event!(tx, revert_event);
+            crate::send_event!(
+                tx,
+                Event::SendStatus(Some(format!("Timed {} enable expired", name)))
+            );
+        }));
+    }
+
+    pub(crate) fn extend(&mut self, tx: &Sender<Event>, duration: Duration) {
+        if let Some(remaining) = self.remaining() {
+            self.start(tx, remaining + duration);
+        }
+    }
+
+    pub(crate) fn cancel(&mut self) {
+        self.deadline = None;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}